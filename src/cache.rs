@@ -0,0 +1,219 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::pit::Pit;
+
+/// Downloaded PITs stored in `$XDG_CACHE_HOME/wuotan/pit` (or `~/.cache/wuotan/pit`).
+///
+/// Every PIT is saved once under its fingerprint, the project name of the header
+/// followed by the start of the SHA-256 hash of its content, e.g.
+/// `MSM8996-3f2a9c0d5e7b1a64.pit`.
+pub struct PitCache {
+    dir: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct CachedPit {
+    pub fingerprint: String,
+    pub path: PathBuf,
+    pub modified: SystemTime,
+}
+
+impl PitCache {
+    /// Returns the cache of the user or `None` if no cache directory is known.
+    pub fn open() -> Option<PitCache> {
+        let dir = cache_dir()?.join("wuotan").join("pit");
+        Some(PitCache { dir })
+    }
+
+    /// Saves the PIT if it's not cached yet and returns its fingerprint, a corrupt
+    /// copy is replaced.
+    pub fn store(&self, pit: &[u8]) -> Result<String, Error> {
+        let fingerprint = fingerprint(pit);
+        let path = self.dir.join(format!("{}.pit", fingerprint));
+        if fs::read(&path).ok().as_deref() != Some(pit) {
+            fs::create_dir_all(&self.dir)?;
+            fs::write(&path, pit)?;
+            tracing::debug!("cached PIT: {}", path.display());
+        }
+        Ok(fingerprint)
+    }
+
+    /// Loads the PIT with the given fingerprint or an unambiguous prefix of it.
+    ///
+    /// Fails if the content no longer matches the fingerprint.
+    pub fn load(&self, fingerprint: &str) -> Result<Vec<u8>, Error> {
+        let mut found = self
+            .list()?
+            .into_iter()
+            .filter(|cached| cached.fingerprint.starts_with(fingerprint));
+        match (found.next(), found.next()) {
+            (Some(cached), None) => {
+                let pit = fs::read(&cached.path)?;
+                if self::fingerprint(&pit) != cached.fingerprint {
+                    return Err(format!(r#"cached PIT "{}" is corrupt"#, cached.fingerprint).into());
+                }
+                Ok(pit)
+            }
+            (Some(_), Some(_)) => {
+                Err(format!(r#"ambiguous PIT fingerprint "{}""#, fingerprint).into())
+            }
+            (None, _) => Err(format!(r#"no cached PIT "{}""#, fingerprint).into()),
+        }
+    }
+
+    /// Returns the cached PITs, the oldest first.
+    pub fn list(&self) -> Result<Vec<CachedPit>, Error> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut list = vec![];
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "pit") {
+                continue;
+            }
+            let fingerprint = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem.to_string(),
+                None => continue,
+            };
+            let modified = path.metadata()?.modified()?;
+            list.push(CachedPit {
+                fingerprint,
+                path,
+                modified,
+            });
+        }
+        list.sort_by_key(|cached| cached.modified);
+        Ok(list)
+    }
+}
+
+/// Fingerprint of a PIT: the project name of the header and the content hash.
+pub fn fingerprint(pit: &[u8]) -> String {
+    let hash = Sha256::digest(pit);
+    let hash: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", project_name(pit), hash)
+}
+
+/// Project name of the PIT header, if it's usable in a file name.
+fn project_name(pit: &[u8]) -> String {
+    let name = Pit::from_read(pit)
+        .map(|pit| pit.project_name.to_string())
+        .unwrap_or_default();
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        name
+    } else {
+        "unknown".to_string()
+    }
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PIT without entries of the given project.
+    fn pit(project_name: &[u8]) -> Vec<u8> {
+        let mut pit = vec![];
+        pit.extend_from_slice(&0x12349876u32.to_le_bytes());
+        pit.extend_from_slice(&0u32.to_le_bytes());
+        pit.extend_from_slice(b"COM_TAR2");
+        let mut name = project_name.to_vec();
+        name.resize(8, 0);
+        pit.extend(name);
+        pit.extend_from_slice(&[0; 4]);
+        pit
+    }
+
+    fn cache(name: &str) -> PitCache {
+        let dir = env::temp_dir().join(format!("wuotan-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        PitCache { dir }
+    }
+
+    #[test]
+    fn derive_fingerprint() {
+        let fingerprint = super::fingerprint(&pit(b"MSM8996"));
+        let (name, hash) = fingerprint.split_once('-').unwrap();
+        assert_eq!(name, "MSM8996");
+        assert_eq!(hash.len(), 16);
+        assert!(hash.bytes().all(|c| c.is_ascii_hexdigit()));
+
+        // the hash covers the whole content
+        let mut other = pit(b"MSM8996");
+        other.push(0);
+        assert_ne!(super::fingerprint(&other), fingerprint);
+        assert_eq!(super::fingerprint(&pit(b"MSM8996")), fingerprint);
+
+        assert!(super::fingerprint(&pit(b"a/b")).starts_with("unknown-"));
+        assert!(super::fingerprint(&pit(b"")).starts_with("unknown-"));
+        assert!(super::fingerprint(b"no pit").starts_with("unknown-"));
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = cache("hit");
+        assert!(cache.list().unwrap().is_empty());
+        assert!(cache.load("MSM8996").is_err());
+
+        let data = pit(b"MSM8996");
+        let fingerprint = cache.store(&data).unwrap();
+        assert_eq!(cache.store(&data).unwrap(), fingerprint);
+        assert_eq!(cache.load(&fingerprint).unwrap(), data);
+        assert_eq!(cache.load("MSM8996").unwrap(), data);
+        let e = cache.load("SDM845").unwrap_err();
+        assert_eq!(e.to_string(), r#"no cached PIT "SDM845""#);
+
+        let mut other = data.clone();
+        other.push(0);
+        cache.store(&other).unwrap();
+        let list = cache.list().unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.iter().any(|cached| cached.fingerprint == fingerprint));
+        let e = cache.load("MSM8996").unwrap_err();
+        assert_eq!(e.to_string(), r#"ambiguous PIT fingerprint "MSM8996""#);
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entry() {
+        let cache = cache("corrupt");
+        let data = pit(b"MSM8996");
+        let fingerprint = cache.store(&data).unwrap();
+        let path = cache.dir.join(format!("{}.pit", fingerprint));
+        fs::write(&path, &data[..20]).unwrap();
+
+        let e = cache.load(&fingerprint).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(r#"cached PIT "{}" is corrupt"#, fingerprint)
+        );
+
+        // storing the PIT again repairs the entry
+        cache.store(&data).unwrap();
+        assert_eq!(cache.load(&fingerprint).unwrap(), data);
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -1,17 +1,40 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
 use clap::{Arg, ArgMatches};
 
+mod decode;
 mod detect;
 mod flash;
+pub mod heimdall;
 mod pit;
 mod reboot;
 
-use crate::device::{self, Device};
-use crate::error::{CliResult, Error};
+use crate::cache::PitCache;
+use crate::config::{Config, Settings};
+use crate::device::{self, Device, DeviceMatch, Devices, Handle};
+use crate::error::{CliResult, Error, ErrorKind};
+use crate::events::{self, Event};
+use crate::proto;
+use crate::session::Session;
+use wuotan::pcap::PcapngWriter;
+use wuotan::record::Recorder;
 
 pub type App = clap::App<'static>;
 
+/// Timeout of the USB transfers if none is configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub fn cli() -> Vec<App> {
-    vec![detect::cli(), pit::cli(), flash::cli(), reboot::cli()]
+    vec![
+        detect::cli(),
+        pit::cli(),
+        flash::cli(),
+        reboot::cli(),
+        decode::cli(),
+        heimdall::cli(),
+    ]
 }
 
 pub fn get(cmd: &str) -> Option<fn(&ArgMatches) -> CliResult> {
@@ -20,18 +43,42 @@ pub fn get(cmd: &str) -> Option<fn(&ArgMatches) -> CliResult> {
         "pit" => pit::exec,
         "flash" => flash::exec,
         "reboot" => reboot::exec,
+        "decode" => decode::exec,
+        "heimdall" => heimdall::exec,
         _ => return None,
     };
     Some(func)
 }
 
 pub trait AppExt {
+    fn arg_profile(self) -> App;
+
     fn arg_usb_log_level(self) -> App;
 
+    fn arg_usb_id(self) -> App;
+
     fn arg_select_device(self) -> App;
+
+    fn arg_capture(self) -> App;
+
+    fn arg_pit_cache(self) -> App;
+
+    fn arg_events(self) -> App;
 }
 
 impl AppExt for App {
+    fn arg_profile(self) -> App {
+        self.arg(
+            opt(
+                "profile",
+                "use the settings of a profile from the config file",
+            )
+            .global(true)
+            .takes_value(true)
+            .value_name("NAME"),
+        )
+    }
+
     fn arg_usb_log_level(self) -> App {
         self.arg(
             Arg::new("usb-log-level")
@@ -40,7 +87,22 @@ impl AppExt for App {
                 .global(true)
                 .takes_value(true)
                 .value_name("LEVEL")
-                .possible_values(&["error", "warn", "info", "debug"]),
+                .possible_values(["error", "warn", "info", "debug"]),
+        )
+    }
+
+    fn arg_usb_id(self) -> App {
+        self.arg(
+            Arg::new("usb-id")
+                .long("usb-id")
+                .help(
+                    "additional vendor/product id of a device in download mode (ex: \"04e8:685d\")",
+                )
+                .global(true)
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("VID:PID[:CLASS]")
+                .validator(|s| s.parse::<DeviceMatch>().map(|_| ())),
         )
     }
 
@@ -63,32 +125,124 @@ impl AppExt for App {
                 }),
         )
     }
+
+    fn arg_capture(self) -> App {
+        self.arg(
+            path_opt("record", "record the USB traffic of the session to a file")
+                .value_name("FILE"),
+        )
+        .arg(
+            path_opt(
+                "pcap",
+                "write the USB traffic of the session to a pcapng file",
+            )
+            .value_name("FILE"),
+        )
+    }
+
+    fn arg_pit_cache(self) -> App {
+        self.arg(
+            opt(
+                "pit-cache",
+                "use a cached PIT instead of a connected device (see `pit cache list`)",
+            )
+            .takes_value(true)
+            .value_name("FINGERPRINT")
+            .conflicts_with("device"),
+        )
+    }
+
+    fn arg_events(self) -> App {
+        self.arg(
+            opt("events", "write progress events to stdout")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(["json"]),
+        )
+        .arg(
+            opt(
+                "events-fd",
+                "write the events to a file descriptor instead of stdout",
+            )
+            .takes_value(true)
+            .value_name("FD")
+            .requires("events"),
+        )
+    }
 }
 
 pub trait ArgMatchesExt {
-    fn usb_log_level(&self) -> Option<rusb::LogLevel>;
+    fn settings(&self) -> Result<Settings, Error>;
+
+    fn usb_log_level(&self) -> Result<Option<rusb::LogLevel>, Error>;
+
+    fn device_matches(&self) -> Result<Vec<DeviceMatch>, Error>;
+
+    fn detect_devices(&self) -> Result<Devices, Error>;
+
+    fn selected_device(&self) -> Result<Device, Error>;
 
-    fn selected_device(&self) -> Result<Option<Device>, Error>;
+    fn open_device(&self, device: &Device) -> Result<Handle, Error>;
+
+    fn cached_pit(&self) -> Result<Option<Vec<u8>>, Error>;
+
+    fn connect(&self, device: &Device) -> Result<Session, Error>;
+
+    fn open_session(&self, device: &Device) -> Result<Session, Error>;
 }
 
 impl ArgMatchesExt for ArgMatches {
-    fn usb_log_level(&self) -> Option<rusb::LogLevel> {
-        let level = match self.value_of("usb-log-level") {
+    /// Settings of the config file and the selected profile, overridden by the
+    /// command line arguments.
+    fn settings(&self) -> Result<Settings, Error> {
+        let mut settings = Config::get()?.settings(self.value_of("profile"))?;
+        if let Some(level) = self.value_of("usb-log-level") {
+            settings.usb_log_level = Some(level.to_string());
+        }
+        Ok(settings)
+    }
+
+    fn usb_log_level(&self) -> Result<Option<rusb::LogLevel>, Error> {
+        let level = match self.settings()?.usb_log_level.as_deref() {
             Some("error") => rusb::LogLevel::Error,
             Some("warn") => rusb::LogLevel::Warning,
             Some("info") => rusb::LogLevel::Info,
             Some("debug") => rusb::LogLevel::Debug,
-            _ => return None,
+            Some(level) => return Err(format!(r#"invalid usb log level "{}""#, level).into()),
+            None => return Ok(None),
         };
-        Some(level)
+        Ok(Some(level))
+    }
+
+    fn device_matches(&self) -> Result<Vec<DeviceMatch>, Error> {
+        let mut matches = device::DOWNLOAD_MODE_IDS.to_vec();
+        matches.extend(Config::get()?.usb_ids()?);
+        if let Some(values) = self.values_of("usb-id") {
+            for value in values {
+                matches.push(value.parse()?);
+            }
+        }
+        Ok(matches)
+    }
+
+    fn detect_devices(&self) -> Result<Devices, Error> {
+        let level = self.usb_log_level()?;
+        let matches = self.device_matches()?;
+        Ok(device::detect(level, &matches)?)
     }
 
-    fn selected_device(&self) -> Result<Option<Device>, Error> {
-        let level = self.usb_log_level();
-        let mut it = device::detect(level)?.into_iter();
+    fn selected_device(&self) -> Result<Device, Error> {
+        let mut it = self.detect_devices()?.into_iter();
 
-        let device = match self.value_of("device").and_then(|s| s.split_once(':')) {
-            Some((bus_number, address)) => {
+        let selector = match self.value_of("device") {
+            Some(selector) => Some(selector.to_string()),
+            None => self.settings()?.device,
+        };
+        let device = match &selector {
+            Some(selector) => {
+                let (bus_number, address) = selector
+                    .split_once(':')
+                    .ok_or_else(|| format!(r#"invalid device selector "{}""#, selector))?;
                 let bus_number = bus_number.parse::<u8>()?;
                 let address = address.parse::<u8>()?;
                 it.find(|d| d.bus_number() == bus_number && d.address() == address)
@@ -96,8 +250,154 @@ impl ArgMatchesExt for ArgMatches {
             None => it.next(),
         };
 
-        Ok(device)
+        device.ok_or_else(|| NoDeviceError { selector }.into())
+    }
+
+    fn open_device(&self, device: &Device) -> Result<Handle, Error> {
+        let timeout = self.settings()?.timeout().unwrap_or(DEFAULT_TIMEOUT);
+        let mut handle = device.open(timeout)?;
+        if let Some(path) = self.value_of_os("record") {
+            handle.add_capture(Recorder::create(path)?);
+        }
+        if let Some(path) = self.value_of_os("pcap") {
+            let pcap = PcapngWriter::create(path, device.bus_number(), device.address())?;
+            handle.add_capture(pcap);
+        }
+        Ok(handle)
+    }
+
+    /// Opens the device and does the handshake, emitting the events of the steps.
+    fn connect(&self, device: &Device) -> Result<Session, Error> {
+        let handle = self.open_device(device)?;
+        let (vendor_id, product_id) = device.id()?;
+        events::emit(Event::DeviceOpened {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id,
+            product_id,
+        });
+
+        let session = Session::connect(handle)?;
+        events::emit(Event::Handshake);
+        Ok(session)
+    }
+
+    /// Opens the device and begins a session, emitting the events of the steps.
+    fn open_session(&self, device: &Device) -> Result<Session, Error> {
+        let mut session = self.connect(device)?;
+        session.begin()?;
+        events::emit(Event::SessionStarted {
+            packet_size: session.default_packet_size(),
+        });
+        Ok(session)
+    }
+
+    /// The PIT selected with `--pit-cache`.
+    fn cached_pit(&self) -> Result<Option<Vec<u8>>, Error> {
+        match self.value_of("pit-cache") {
+            Some(fingerprint) => {
+                let cache = PitCache::open().ok_or("no cache directory")?;
+                Ok(Some(cache.load(fingerprint)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Class of an error, reported in the `error` event.
+pub fn error_kind(err: &Error) -> ErrorKind {
+    fn usb_kind(err: &rusb::Error) -> ErrorKind {
+        match err {
+            rusb::Error::Access => ErrorKind::PermissionDenied,
+            rusb::Error::NoDevice => ErrorKind::NoDevice,
+            _ => ErrorKind::Usb,
+        }
+    }
+    fn io_kind(err: &io::Error) -> ErrorKind {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Io,
+        }
+    }
+
+    if err.is::<NoDeviceError>() {
+        return ErrorKind::NoDevice;
+    }
+    if let Some(err) = err.downcast_ref::<flash::FlashError>() {
+        return err.kind();
+    }
+    if let Some(err) = err.downcast_ref::<proto::Error>() {
+        return match err {
+            proto::Error::Handshake => ErrorKind::Handshake,
+            proto::Error::Usb(e) => usb_kind(e),
+            proto::Error::Io(e) => io_kind(e),
+            proto::Error::Cancelled => ErrorKind::Interrupted,
+            _ => ErrorKind::Protocol,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<rusb::Error>() {
+        return usb_kind(err);
+    }
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        return io_kind(err);
+    }
+    ErrorKind::Other
+}
+
+/// No device in download mode is connected or the selected device was not found.
+#[derive(Debug)]
+pub struct NoDeviceError {
+    selector: Option<String>,
+}
+
+impl std::error::Error for NoDeviceError {}
+
+impl fmt::Display for NoDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.selector {
+            Some(selector) => write!(f, r#"device "{}" not found"#, selector),
+            None => f.write_str("no device found"),
+        }
+    }
+}
+
+/// Saves a PIT received from a device in the cache, failures are only logged.
+pub fn cache_pit(pit: &[u8]) {
+    if let Some(cache) = PitCache::open() {
+        if let Err(e) = cache.store(pit) {
+            tracing::warn!("failed to cache PIT: {}", e);
+        }
+    }
+}
+
+/// Matches a name against a wildcard pattern with `*` and `?`, ignoring the case.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase().into_bytes();
+    let name = name.to_ascii_lowercase().into_bytes();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                }
+                None => return false,
+            },
+        }
     }
+    pattern[p..].iter().all(|&c| c == b'*')
 }
 
 pub fn opt(name: &'static str, help: &'static str) -> Arg<'static> {
@@ -108,3 +408,130 @@ pub fn opt(name: &'static str, help: &'static str) -> Arg<'static> {
 pub fn path_opt(name: &'static str, help: &'static str) -> Arg<'static> {
     opt(name, help).allow_invalid_utf8(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::flash::FlashError;
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let io_error = |kind| Error::from(io::Error::new(kind, "failed"));
+        let cases: Vec<(Error, ErrorKind, u8)> = vec![
+            ("other".into(), ErrorKind::Other, 1),
+            (io_error(io::ErrorKind::NotFound), ErrorKind::Io, 1),
+            (
+                FlashError::InvalidFile("a.img".into()).into(),
+                ErrorKind::InvalidFile,
+                1,
+            ),
+            (
+                NoDeviceError { selector: None }.into(),
+                ErrorKind::NoDevice,
+                3,
+            ),
+            (rusb::Error::NoDevice.into(), ErrorKind::NoDevice, 3),
+            (rusb::Error::Access.into(), ErrorKind::PermissionDenied, 4),
+            (
+                io_error(io::ErrorKind::PermissionDenied),
+                ErrorKind::PermissionDenied,
+                4,
+            ),
+            (proto::Error::Handshake.into(), ErrorKind::Handshake, 5),
+            (
+                proto::Error::InvalidPacket(vec![]).into(),
+                ErrorKind::Protocol,
+                6,
+            ),
+            (
+                proto::Error::Replay("diverged".into()).into(),
+                ErrorKind::Protocol,
+                6,
+            ),
+            (
+                proto::Error::Usb(rusb::Error::Pipe).into(),
+                ErrorKind::Usb,
+                6,
+            ),
+            (
+                proto::Error::Usb(rusb::Error::Access).into(),
+                ErrorKind::PermissionDenied,
+                4,
+            ),
+            (
+                proto::Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "eof")).into(),
+                ErrorKind::Io,
+                1,
+            ),
+            (rusb::Error::Timeout.into(), ErrorKind::Usb, 6),
+            (
+                FlashError::InvalidChecksum("AP.tar.md5".into()).into(),
+                ErrorKind::Checksum,
+                7,
+            ),
+            (FlashError::PitMismatch.into(), ErrorKind::PitMismatch, 8),
+            (
+                FlashError::PartitionNotFound("BOOT".into()).into(),
+                ErrorKind::PartitionNotFound,
+                9,
+            ),
+            (
+                FlashError::FlashNameNotFound("boot.img".into()).into(),
+                ErrorKind::PartitionNotFound,
+                9,
+            ),
+            (FlashError::Interrupted.into(), ErrorKind::Interrupted, 130),
+            (proto::Error::Cancelled.into(), ErrorKind::Interrupted, 130),
+        ];
+        for (err, kind, code) in cases {
+            assert_eq!(error_kind(&err), kind, "{}", err);
+            assert_eq!(kind.exit_code(), code, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn match_wildcards() {
+        assert!(wildcard_match("BOOT", "BOOT"));
+        assert!(!wildcard_match("BOOT", "BOOTLOADER"));
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "BOOT"));
+
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "BOOT"));
+        assert!(wildcard_match("BOOT*", "BOOTLOADER"));
+        assert!(wildcard_match("*LOADER", "BOOTLOADER"));
+        assert!(wildcard_match("**", "BOOT"));
+        assert!(!wildcard_match("*LOADER", "BOOT"));
+    }
+
+    #[test]
+    fn match_wildcards_backtracking() {
+        // the first `*` has to give back characters for the rest to match
+        assert!(wildcard_match("*A*B", "AAXAAB"));
+        assert!(wildcard_match("*AB", "AAAB"));
+        assert!(wildcard_match("S*M*", "SYSTEM"));
+        assert!(wildcard_match("*_*_*", "A_B_C_D"));
+        assert!(!wildcard_match("*A*B", "AXBA"));
+        assert!(!wildcard_match("S*M*X", "SYSTEM"));
+    }
+
+    #[test]
+    fn match_single_characters() {
+        assert!(wildcard_match("?", "A"));
+        assert!(!wildcard_match("?", ""));
+        assert!(!wildcard_match("?", "AB"));
+        assert!(wildcard_match("CACHE?", "CACHE1"));
+        assert!(!wildcard_match("CACHE?", "CACHE"));
+        assert!(wildcard_match("?*", "A"));
+        assert!(wildcard_match("*?", "AB"));
+        assert!(!wildcard_match("??*", "A"));
+    }
+
+    #[test]
+    fn match_ignoring_case() {
+        assert!(wildcard_match("boot", "BOOT"));
+        assert!(wildcard_match("Sys*", "SYSTEM"));
+        assert!(wildcard_match("*LOADER", "sboot_loader"));
+        assert!(wildcard_match("?ache", "CACHE"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use clap::{Arg, ArgMatches};
+
+use super::{App, CliResult};
+use wuotan::proto::decode::Decoder;
+use wuotan::record::{self, Direction};
+
+pub fn cli() -> App {
+    App::new("decode")
+        .about("decode the packets of a recorded session or a hex dump")
+        .after_help(
+            "Hex dumps are read line by line. Lines with packets are either taken from the \
+             debug logs (`out: [64, 0, ...]`, `in: [...]`) or start with `>` (out) or `<` (in) \
+             followed by the bytes in hex (`> 64 00 00 00`).",
+        )
+        .arg(
+            Arg::new("file")
+                .value_name("FILE")
+                .required(true)
+                .allow_invalid_utf8(true)
+                .help("recorded session or hex dump"),
+        )
+}
+
+pub fn exec(args: &ArgMatches) -> CliResult {
+    let path = args.value_of_os("file").expect("argument is required");
+
+    let mut magic = [0; 4];
+    let n = File::open(path)?.read(&mut magic)?;
+
+    let mut decoder = Decoder::new();
+    if &magic[..n] == b"WREC" {
+        for transfer in record::Reader::open(path)? {
+            let transfer = transfer?;
+            let ts = transfer.timestamp.as_secs_f64();
+            let dir = arrow(transfer.direction);
+            match transfer.result {
+                Ok(data) => println!(
+                    "{:>12.6} {} {}",
+                    ts,
+                    dir,
+                    decoder.decode(transfer.direction, &data)
+                ),
+                Err(e) => println!("{:>12.6} {} ERROR {}", ts, dir, e),
+            }
+        }
+    } else {
+        let input = BufReader::new(File::open(path)?);
+        for (i, line) in input.lines().enumerate() {
+            if let Some((direction, data)) = parse_line(&line?) {
+                let msg = decoder.decode(direction, &data);
+                println!("{:>6} {} {}", i + 1, arrow(direction), msg);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Out => ">",
+        Direction::In => "<",
+    }
+}
+
+fn parse_line(line: &str) -> Option<(Direction, Vec<u8>)> {
+    let trimmed = line.trim_start();
+    if let Some(hex) = trimmed.strip_prefix('>') {
+        return parse_hex(hex.split_whitespace()).map(|data| (Direction::Out, data));
+    }
+    if let Some(hex) = trimmed.strip_prefix('<') {
+        return parse_hex(hex.split_whitespace()).map(|data| (Direction::In, data));
+    }
+
+    // debug logs: `out: [64, 0, 0, 0]` or `in:  [64, 0, 0, 0]`
+    let (direction, rest) = match (line.find("out: ["), line.find("in: ["), line.find("in:  [")) {
+        (Some(pos), _, _) => (Direction::Out, &line[pos..]),
+        (_, Some(pos), _) | (_, _, Some(pos)) => (Direction::In, &line[pos..]),
+        _ => return None,
+    };
+    let start = rest.find('[')? + 1;
+    let end = rest.find(']')?;
+    let list = &rest[start..end];
+    let bytes = list.split(',').map(str::trim).filter(|s| !s.is_empty());
+    parse_hex(bytes).map(|data| (direction, data))
+}
+
+fn parse_hex<'a, I: Iterator<Item = &'a str>>(values: I) -> Option<Vec<u8>> {
+    values.map(|s| u8::from_str_radix(s, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_dump_lines() {
+        assert_eq!(
+            parse_line("> 64 00 00 00"),
+            Some((Direction::Out, vec![0x64, 0, 0, 0]))
+        );
+        assert_eq!(
+            parse_line("  < 4c 4F 4b 45"),
+            Some((Direction::In, b"LOKE".to_vec()))
+        );
+        assert_eq!(parse_line(">"), Some((Direction::Out, vec![])));
+        assert_eq!(parse_line("> 64 zz"), None);
+    }
+
+    #[test]
+    fn parse_debug_log_lines() {
+        let line = "2024-01-01T00:00:00Z DEBUG wuotan::proto: out: [64, 0, 0, 0, A, 0]";
+        assert_eq!(
+            parse_line(line),
+            Some((Direction::Out, vec![0x64, 0, 0, 0, 0x0a, 0]))
+        );
+        assert_eq!(
+            parse_line("DEBUG in:  [64, 0, 0, 0, 0, 0, 10, 0]"),
+            Some((Direction::In, vec![0x64, 0, 0, 0, 0, 0, 0x10, 0]))
+        );
+        assert_eq!(
+            parse_line("DEBUG n=500 in: [FF]"),
+            Some((Direction::In, vec![0xff]))
+        );
+    }
+
+    #[test]
+    fn ignore_other_lines() {
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("INFO flashing BOOT"), None);
+        assert_eq!(parse_line("DEBUG out: [64, 0"), None);
+    }
+}
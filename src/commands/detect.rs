@@ -1,24 +1,188 @@
 use clap::ArgMatches;
+use serde::Serialize;
 
-use super::{App, ArgMatchesExt, CliResult};
-use crate::device;
+use super::{opt, App, ArgMatchesExt, CliResult, NoDeviceError};
+use crate::device::{Device, Functions, OtherDevice};
 
 pub fn cli() -> App {
-    App::new("detect").about("list connected Samsung devices")
+    App::new("detect")
+        .about("list connected Samsung devices")
+        .arg(opt("json", "print the device details as JSON"))
+        .arg(opt(
+            "fail-if-none",
+            "exit with an error if no device in download mode is found",
+        ))
 }
 
 pub fn exec(args: &ArgMatches) -> CliResult {
-    let log_level = args.usb_log_level();
-    for device in device::detect(log_level)? {
-        let (vendor_id, product_id) = device.id()?;
-        println!(
+    let devices = args.detect_devices()?;
+
+    let others = devices
+        .others()
+        .iter()
+        .map(OtherDeviceInfo::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut infos = vec![];
+    for device in devices {
+        infos.push(DeviceInfo::new(&device)?);
+    }
+    let none_found = infos.is_empty();
+
+    if args.is_present("json") {
+        let entries = infos
+            .into_iter()
+            .map(Entry::Download)
+            .chain(others.into_iter().map(Entry::Other))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return check_found(args, none_found);
+    }
+
+    for info in infos {
+        print!(
             "Bus {:03} Device {:03}: ID {:04x}:{:04x}",
-            device.bus_number(),
-            device.address(),
-            vendor_id,
-            product_id
+            info.bus_number, info.address, info.vendor_id, info.product_id
+        );
+        for s in info.manufacturer.iter().chain(&info.product) {
+            print!(" {}", s);
+        }
+        println!();
+        println!("  Port:      {}", info.port_path.as_deref().unwrap_or("-"));
+        println!("  Speed:     {}", info.speed);
+        println!(
+            "  Serial:    {}",
+            info.serial_number.as_deref().unwrap_or("-")
+        );
+        println!(
+            "  Interface: {} alt {} (in=0x{:02x}, out=0x{:02x})",
+            info.interface.number,
+            info.interface.alt_setting,
+            info.interface.read_endpoint,
+            info.interface.write_endpoint
+        );
+        match info.claim_error {
+            None => println!("  Claimable: yes"),
+            Some(err) => println!("  Claimable: no ({})", err),
+        }
+    }
+
+    for info in others {
+        println!(
+            "Bus {:03} Device {:03}: ID {:04x}:{:04x} ({}, not in download mode)",
+            info.bus_number, info.address, info.vendor_id, info.product_id, info.description
         );
     }
 
+    check_found(args, none_found)
+}
+
+fn check_found(args: &ArgMatches, none_found: bool) -> CliResult {
+    if none_found && args.is_present("fail-if-none") {
+        return Err(NoDeviceError { selector: None }.into());
+    }
     Ok(())
 }
+
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+enum Entry {
+    Download(DeviceInfo),
+    Other(OtherDeviceInfo),
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    bus_number: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+    port_path: Option<String>,
+    speed: &'static str,
+    interface: InterfaceInfo,
+    claim_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct InterfaceInfo {
+    number: u8,
+    alt_setting: u8,
+    read_endpoint: u8,
+    write_endpoint: u8,
+}
+
+impl DeviceInfo {
+    fn new(device: &Device) -> Result<Self, rusb::Error> {
+        let (vendor_id, product_id) = device.id()?;
+        let strings = device.strings().unwrap_or_default();
+        let claim_error = device.check_claim().err().map(|e| e.to_string());
+
+        Ok(Self {
+            bus_number: device.bus_number(),
+            address: device.address(),
+            vendor_id,
+            product_id,
+            manufacturer: strings.manufacturer,
+            product: strings.product,
+            serial_number: strings.serial_number,
+            port_path: device.port_path().ok(),
+            speed: speed_name(device.speed()),
+            interface: InterfaceInfo {
+                number: device.interface_number(),
+                alt_setting: device.alt_setting(),
+                read_endpoint: device.read_endpoint(),
+                write_endpoint: device.write_endpoint(),
+            },
+            claim_error,
+        })
+    }
+}
+
+fn speed_name(speed: rusb::Speed) -> &'static str {
+    match speed {
+        rusb::Speed::Low => "low (1.5 Mbps)",
+        rusb::Speed::Full => "full (12 Mbps)",
+        rusb::Speed::High => "high (480 Mbps)",
+        rusb::Speed::Super => "super (5 Gbps)",
+        rusb::Speed::SuperPlus => "super+ (10 Gbps)",
+        _ => "unknown",
+    }
+}
+
+#[derive(Serialize)]
+struct OtherDeviceInfo {
+    bus_number: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    #[serde(skip)]
+    description: String,
+    functions: Vec<&'static str>,
+}
+
+impl OtherDeviceInfo {
+    fn new(device: &OtherDevice) -> Result<Self, rusb::Error> {
+        let (vendor_id, product_id) = device.id()?;
+        let functions = device.functions();
+
+        let mut names = vec![];
+        if functions.contains(Functions::MTP) {
+            names.push("mtp");
+        }
+        if functions.contains(Functions::ADB) {
+            names.push("adb");
+        }
+
+        Ok(Self {
+            bus_number: device.bus_number(),
+            address: device.address(),
+            vendor_id,
+            product_id,
+            description: functions.to_string(),
+            functions: names,
+        })
+    }
+}
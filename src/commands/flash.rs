@@ -1,21 +1,31 @@
-use std::borrow::Cow;
-use std::fs::File;
-use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
-use std::path::Path;
-use std::time::Duration;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 use clap::{ArgGroup, ArgMatches};
-use md5::{Digest, Md5};
 
-use super::{opt, path_opt, App, AppExt, ArgMatchesExt, CliResult, Error};
+use super::{cache_pit, opt, path_opt, App, AppExt, ArgMatchesExt, CliResult, Error};
+use crate::error::ErrorKind;
+use crate::events::{self, Event, Progress};
 use crate::pit::{BinaryType, Entry, Pit};
-use crate::proto::{self, FileTarget};
+use crate::proto::FileTarget;
+
+mod recipe;
+mod report;
+mod verify;
+
+use recipe::{Recipe, TarFilter};
+use report::{Hashing, Outcome, Report};
+use verify::{is_tar_md5, verify_tar_checksum, TarChecksum, Verifying};
 
 pub fn cli() -> App {
     App::new("flash")
         .about("flash partitions to a connected device")
         .arg(
-            path_opt("part", "partition name and file image")
+            path_opt("part", "partition name (or identifier) and file image")
                 .short('p')
                 .value_names(&["NAME", "FILE"])
                 .multiple_occurrences(true)
@@ -27,116 +37,405 @@ pub fn cli() -> App {
                 .value_name("FILE")
                 .multiple_occurrences(true),
         )
+        .arg(
+            path_opt("recipe", "flash job described in a TOML file")
+                .value_name("FILE")
+                .conflicts_with_all(&["tar", "part"]),
+        )
         .group(
             ArgGroup::new("files")
                 .multiple(true)
                 .required(true)
-                .args(&["tar", "part"]),
+                .args(&["tar", "part", "recipe"]),
         )
         .arg(opt("no-verify", "don't verify the checksum of tar files"))
+        .arg(
+            opt(
+                "verify",
+                "verify the checksum of tar files even if the config or recipe disables it",
+            )
+            .conflicts_with("no-verify"),
+        )
+        .arg(
+            opt(
+                "verify-while-flashing",
+                "verify the checksum of tar files while flashing instead of before, a mismatch \
+                 only keeps the last partition of a tar from being written",
+            )
+            .conflicts_with_all(&["no-verify", "pit-cache"]),
+        )
         .arg(opt("reboot", "reboot device after upload"))
         .arg_select_device()
+        .arg(
+            path_opt("report", "write an audit report of the flash as JSON")
+                .value_name("FILE")
+                .conflicts_with("pit-cache"),
+        )
+        .arg_pit_cache()
+        .arg_events()
+        .arg_capture()
 }
 
-pub fn exec(args: &ArgMatches) -> CliResult {
-    let files = get_arguments(args)?;
+/// Set by the first Ctrl-C, the flashing stops after the current batch.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    if let Some(device) = args.selected_device()? {
-        let mut handle = device.open(Duration::from_secs(3))?;
-        handle.claim()?;
-        handle.reset()?;
+fn set_interrupt_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            eprintln!("Aborted");
+            process::exit(130);
+        }
+        eprintln!("Interrupted, stopping after the current batch (press Ctrl-C again to abort)");
+    })
+}
 
-        proto::handshake(&handle)?;
+pub fn exec(args: &ArgMatches) -> CliResult {
+    events::init(args)?;
+    let recipe = match args.value_of_os("recipe") {
+        Some(path) => Some(Recipe::load(Path::new(path))?),
+        None => None,
+    };
+    let files = match &recipe {
+        Some(recipe) => recipe.files()?,
+        None => get_arguments(args),
+    };
 
-        if proto::begin_session(&handle)? != 0 {
-            proto::setup_file_part_size(&handle, 1048576)?; // 1MB
-        }
+    let settings = args.settings()?;
+    let recipe_verify = recipe.as_ref().and_then(|r| r.verify);
+    let verify = if args.is_present("verify") || args.is_present("verify-while-flashing") {
+        true
+    } else {
+        !args.is_present("no-verify")
+            && recipe_verify
+                .or(settings.no_verify.map(|v| !v))
+                .unwrap_or(true)
+    };
+    let recipe_reboot = recipe.as_ref().and_then(|r| r.reboot);
+    let reboot = args.is_present("reboot") || recipe_reboot.or(settings.reboot).unwrap_or(false);
 
-        let pit = proto::receive_pit(&handle)?;
-        let pit = Pit::from_read(&mut Cursor::new(pit))?;
+    let stream_verify = verify && args.is_present("verify-while-flashing");
 
-        let (total_file_size, mapped_args) = map_arguments_with_pit(&files, &pit)?;
+    check_files(&files, verify && !stream_verify)?;
 
-        proto::send_total_size(&handle, total_file_size)?;
+    let expected_pit = match recipe.as_ref().and_then(|r| r.pit.as_ref()) {
+        Some(path) => Some(fs::read(path)?),
+        None => None,
+    };
+    let repartition = recipe.as_ref().is_some_and(|r| r.repartition);
 
-        let target_for_entry = |entry: &Entry| match entry.binary_type {
-            BinaryType::ApplicationProcessor => FileTarget::ApplicationProcessor {
-                device_type: entry.device_type.as_u32(),
-                identifier: entry.identifier,
-            },
-            BinaryType::CommunicationProcessor => FileTarget::CommunicationProcessor {
-                device_type: entry.device_type.as_u32(),
-            },
-            BinaryType::Unknown(_) => todo!(),
+    if let Some(cached) = args.cached_pit()? {
+        let pit = match expected_pit {
+            Some(expected) if repartition => expected,
+            Some(expected) if !same_partitions(&cached, &expected)? => {
+                return Err(FlashError::PitMismatch.into())
+            }
+            _ => cached,
         };
+        let pit = Pit::from_read(&mut Cursor::new(pit))?;
+        return print_plan(&files, &pit);
+    }
 
-        for entry in mapped_args {
-            match entry {
-                MappedEntry::Partition { file, entry } => {
-                    println!("Uploading {}", entry.partition_name);
+    // created before flashing to fail early if it can't be written
+    let report_file = match args.value_of_os("report") {
+        Some(path) => Some(File::create(path)?),
+        None => None,
+    };
+    let mut report = Report::new();
+    let result = flash(
+        args,
+        &files,
+        expected_pit,
+        repartition,
+        reboot,
+        stream_verify,
+        &mut report,
+    );
+    if let Some(file) = report_file {
+        report.write(file, &result)?;
+    }
+    result
+}
 
-                    let target = target_for_entry(entry);
-                    let file_size = file.metadata()?.len();
-                    let mut file = BufReader::new(File::open(file)?);
+fn flash(
+    args: &ArgMatches,
+    files: &[FileArgument],
+    expected_pit: Option<Vec<u8>>,
+    repartition: bool,
+    reboot: bool,
+    stream_verify: bool,
+    report: &mut Report,
+) -> CliResult {
+    let device = args.selected_device()?;
+    report.device(&device)?;
+    set_interrupt_handler()?;
+
+    let mut session = args.open_session(&device)?;
+
+    let pit = match expected_pit {
+        Some(expected) if repartition => {
+            status!("Repartitioning");
+            session.flash_pit(&expected)?;
+            events::emit(Event::PitFlashed {
+                size: expected.len(),
+            });
+            cache_pit(&expected);
+            expected
+        }
+        Some(expected) => {
+            let pit = session.receive_pit()?;
+            events::emit(Event::PitReceived { size: pit.len() });
+            cache_pit(&pit);
+            if !same_partitions(&pit, &expected)? {
+                return Err(FlashError::PitMismatch.into());
+            }
+            pit
+        }
+        None => {
+            let pit = session.receive_pit()?;
+            events::emit(Event::PitReceived { size: pit.len() });
+            cache_pit(&pit);
+            pit
+        }
+    };
+    report.pit(&pit, repartition);
+    let pit = Pit::from_read(&mut Cursor::new(pit))?;
+
+    let (total_file_size, mapped_args) = map_arguments_with_pit(files, &pit)?;
+
+    session.send_total_size(total_file_size)?;
+    events::emit(Event::TotalSizeSent {
+        size: total_file_size,
+    });
+
+    let target_for_entry = |entry: &Entry| match entry.binary_type {
+        BinaryType::ApplicationProcessor => FileTarget::ApplicationProcessor {
+            device_type: entry.device_type.as_u32(),
+            identifier: entry.identifier,
+        },
+        BinaryType::CommunicationProcessor => FileTarget::CommunicationProcessor {
+            device_type: entry.device_type.as_u32(),
+        },
+        BinaryType::Unknown(_) => todo!(),
+    };
 
-                    proto::file_transfer(&handle, &target, &mut file, file_size)?;
+    let mut written = vec![];
+    'entries: for entry in mapped_args {
+        match entry {
+            MappedEntry::Partition { file, entry } => {
+                if INTERRUPTED.load(Ordering::Relaxed) {
+                    break 'entries;
+                }
+                status!("Uploading {}", entry.partition_name);
+
+                let partition = entry.partition_name.to_string();
+                let target = target_for_entry(entry);
+                let file_size = file.metadata()?.len();
+                let source = file.display().to_string();
+                events::emit(Event::PartitionStart {
+                    partition: &partition,
+                    source: &source,
+                    size: file_size,
+                });
+                report.partition_start(&partition, &source, file_size);
+
+                let mut file = BufReader::new(File::open(file)?);
+                let mut hashing = Hashing::new();
+                let mut progress = Progress::new(&partition, file_size);
+                let result = session.file_transfer_until(
+                    &target,
+                    &mut file,
+                    file_size,
+                    &INTERRUPTED,
+                    |data| {
+                        hashing.update(data);
+                        progress.sent(data.len());
+                    },
+                );
+                report.partition_done(hashing, outcome(&result));
+                let done = result?;
+                if !done {
+                    break 'entries;
                 }
-                MappedEntry::Tar { file, entries } => {
-                    let tar_name = file
-                        .file_name()
-                        .map(|n| n.to_string_lossy())
-                        .unwrap_or_default();
-
-                    let mut file = BufReader::new(File::open(file)?);
-                    for (entry, (pos, file_size)) in entries {
-                        println!("Uploading {}/{}", tar_name, entry.flash_filename);
-
-                        let target = target_for_entry(entry);
-                        {
-                            file.seek(SeekFrom::Start(pos))?;
-                            let mut reader = file.by_ref().take(file_size);
-                            proto::file_transfer(&handle, &target, &mut reader, file_size)?;
-                        }
+                events::emit(Event::PartitionDone {
+                    partition: &partition,
+                    size: file_size,
+                });
+                written.push(partition);
+            }
+            MappedEntry::Tar {
+                file: path,
+                entries,
+            } => {
+                let tar_name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default();
+
+                let mut checksum = match stream_verify && is_tar_md5(path) {
+                    true => Some(TarChecksum::open(path)?),
+                    false => None,
+                };
+                // a failed checksum aborts the transfer with a read error
+                let invalid_checksum = |failed: bool, e: Error| -> Error {
+                    if failed {
+                        FlashError::InvalidChecksum(path.display().to_string()).into()
+                    } else {
+                        e
                     }
+                };
+
+                let count = entries.len();
+                let mut file = BufReader::new(File::open(path)?);
+                for (i, (entry, (pos, file_size))) in entries.into_iter().enumerate() {
+                    if INTERRUPTED.load(Ordering::Relaxed) {
+                        break 'entries;
+                    }
+                    status!("Uploading {}/{}", tar_name, entry.flash_filename);
+
+                    let partition = entry.partition_name.to_string();
+                    let target = target_for_entry(entry);
+                    let source = format!("{}/{}", tar_name, entry.flash_filename);
+                    events::emit(Event::PartitionStart {
+                        partition: &partition,
+                        source: &source,
+                        size: file_size,
+                    });
+                    report.partition_start(&partition, &source, file_size);
+
+                    if let Some(checksum) = checksum.as_mut() {
+                        checksum.skip_to(pos)?;
+                    }
+                    file.seek(SeekFrom::Start(pos))?;
+                    let last = i + 1 == count;
+                    let reader = file.by_ref().take(file_size);
+                    let mut reader = Verifying::new(reader, checksum.as_mut(), file_size, last);
+                    let mut hashing = Hashing::new();
+                    let mut progress = Progress::new(&partition, file_size);
+                    let result = session.file_transfer_until(
+                        &target,
+                        &mut reader,
+                        file_size,
+                        &INTERRUPTED,
+                        |data| {
+                            hashing.update(data);
+                            progress.sent(data.len());
+                        },
+                    );
+                    report.partition_done(hashing, outcome(&result));
+                    let failed = checksum.as_ref().is_some_and(TarChecksum::is_failed);
+                    let done = result.map_err(|e| invalid_checksum(failed, e.into()))?;
+                    if !done {
+                        break 'entries;
+                    }
+                    events::emit(Event::PartitionDone {
+                        partition: &partition,
+                        size: file_size,
+                    });
+                    written.push(partition);
+                }
+                if let Some(checksum) = checksum.as_mut().filter(|c| !c.is_done()) {
+                    checksum
+                        .finish()
+                        .map_err(|e| invalid_checksum(checksum.is_failed(), e.into()))?;
                 }
             }
         }
+    }
 
-        proto::end_session(&handle)?;
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        session.finish()?;
+        events::emit(Event::SessionEnded);
 
-        if args.is_present("reboot") {
-            proto::reboot(&handle)?;
-            println!("Rebooting...");
+        if written.is_empty() {
+            status!("No partition was fully written");
+        } else {
+            status!("Fully written partitions: {}", written.join(", "));
         }
+        return Err(FlashError::Interrupted.into());
+    }
+
+    if reboot {
+        session.reboot()?;
+        events::emit(Event::SessionEnded);
+        events::emit(Event::Rebooting);
+        status!("Rebooting...");
+    } else {
+        session.finish()?;
+        events::emit(Event::SessionEnded);
+    }
+    Ok(())
+}
 
-        handle.release()?;
+fn outcome<E>(result: &Result<bool, E>) -> Outcome {
+    match result {
+        Ok(true) => Outcome::Ok,
+        Ok(false) => Outcome::Interrupted,
+        Err(_) => Outcome::Failed,
     }
+}
+
+/// Prints what would be flashed without connecting to a device.
+fn print_plan(files: &[FileArgument], pit: &Pit) -> CliResult {
+    let (total_file_size, mapped_args) = map_arguments_with_pit(files, pit)?;
+    for entry in mapped_args {
+        match entry {
+            MappedEntry::Partition { file, entry } => {
+                let file_size = file.metadata()?.len();
+                status!(
+                    "{}: {} ({} bytes)",
+                    entry.partition_name,
+                    file.display(),
+                    file_size
+                );
+            }
+            MappedEntry::Tar { file, entries } => {
+                let tar_name = file
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default();
+                for (entry, (_, file_size)) in entries {
+                    status!(
+                        "{}: {}/{} ({} bytes)",
+                        entry.partition_name,
+                        tar_name,
+                        entry.flash_filename,
+                        file_size
+                    );
+                }
+            }
+        }
+    }
+    status!("Total size: {} bytes", total_file_size);
     Ok(())
 }
 
-enum FileArgument<'a> {
-    File { name: Cow<'a, str>, file: &'a Path },
-    Tar { file: &'a Path },
+/// Whether the PITs have the same entries, the padding of the PITs and the bytes
+/// after the NUL of the names aren't compared.
+fn same_partitions(pit: &[u8], expected: &[u8]) -> Result<bool, Error> {
+    let pit = Pit::from_read(pit)?;
+    let expected = Pit::from_read(expected)?;
+    Ok(pit.entries == expected.entries)
+}
+
+enum FileArgument {
+    File { name: String, file: PathBuf },
+    Tar { file: PathBuf, filter: TarFilter },
 }
 
-impl<'a> FileArgument<'a> {
+impl FileArgument {
     fn file(&self) -> &Path {
         match self {
             FileArgument::File { file, .. } => file,
-            FileArgument::Tar { file } => file,
+            FileArgument::Tar { file, .. } => file,
         }
     }
 
     fn is_file(&self) -> bool {
-        let file = match self {
-            FileArgument::File { file, .. } => file,
-            FileArgument::Tar { file } => file,
-        };
-        file.is_file()
+        self.file().is_file()
     }
 }
 
-fn get_arguments(args: &ArgMatches) -> Result<Vec<FileArgument<'_>>, Error> {
+fn get_arguments(args: &ArgMatches) -> Vec<FileArgument> {
     fn chunked<T: Iterator>(mut iter: T) -> impl Iterator<Item = (T::Item, T::Item)> {
         std::iter::from_fn(move || iter.next().zip(iter.next()))
     }
@@ -147,8 +446,8 @@ fn get_arguments(args: &ArgMatches) -> Result<Vec<FileArgument<'_>>, Error> {
     if let Some((indices, values)) = partition_args {
         let indices = chunked(indices).map(|(i, _)| i);
         let values = chunked(values).map(|(name, file)| FileArgument::File {
-            name: name.to_string_lossy(),
-            file: Path::new(file),
+            name: name.to_string_lossy().into_owned(),
+            file: PathBuf::from(file),
         });
         files.extend(indices.zip(values));
     }
@@ -156,62 +455,56 @@ fn get_arguments(args: &ArgMatches) -> Result<Vec<FileArgument<'_>>, Error> {
     let tar_args = args.indices_of("tar").zip(args.values_of_os("tar"));
     if let Some((indices, values)) = tar_args {
         let values = values.map(|file| FileArgument::Tar {
-            file: Path::new(file),
+            file: PathBuf::from(file),
+            filter: TarFilter::default(),
         });
         files.extend(indices.zip(values));
     }
 
     files.sort_unstable_by_key(|(idx, _)| *idx);
 
-    for (_, fs) in &files {
+    files.into_iter().map(|(_, fs)| fs).collect()
+}
+
+fn check_files(files: &[FileArgument], verify: bool) -> Result<(), Error> {
+    for fs in files {
         if !fs.is_file() {
             let err = FlashError::InvalidFile(fs.file().display().to_string());
             return Err(err.into());
         }
-        if args.is_present("no-verify") {
-            continue;
-        }
-        if let FileArgument::Tar { file } = fs {
-            if file.extension().map(|ext| ext == "md5").unwrap_or_default() {
-                println!(
+    }
+    if !verify {
+        return Ok(());
+    }
+
+    let tars = files.iter().filter_map(|fs| match fs {
+        FileArgument::Tar { file, .. } if is_tar_md5(file) => Some(file),
+        _ => None,
+    });
+    // the tar files are verified in parallel, they are usually on different disks
+    // or in the page cache
+    thread::scope(|scope| {
+        let checks = tars
+            .map(|file| {
+                status!(
                     "Verifying tar checksum: {}",
                     file.file_name().map(|n| n.to_string_lossy()).unwrap()
                 );
-                if !verify_tar_checksum(file)? {
-                    let err = FlashError::InvalidChecksum(fs.file().display().to_string());
-                    return Err(err.into());
-                }
+                (file, scope.spawn(move || verify_tar_checksum(file)))
+            })
+            .collect::<Vec<_>>();
+
+        for (file, check) in checks {
+            let valid = check
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+            if !valid {
+                let err = FlashError::InvalidChecksum(file.display().to_string());
+                return Err(err.into());
             }
         }
-    }
-
-    Ok(files.into_iter().map(|(_, fs)| fs).collect())
-}
-
-fn verify_tar_checksum(file: &Path) -> Result<bool, io::Error> {
-    let file_size = file.metadata()?.len();
-    // tar_size = file_size - (checksum(32) + space(2) + basename + newline)
-    let tar_size = file
-        .file_stem()
-        .map(|name| 32 + 2 + name.len() as u64 + 1)
-        .and_then(|checksum_len| file_size.checked_sub(checksum_len))
-        .unwrap_or_default();
-
-    let mut reader = BufReader::new(File::open(file)?);
-
-    let calculated = {
-        let mut tar = reader.by_ref().take(tar_size);
-        let mut digest = Md5::new();
-
-        io::copy(&mut tar, &mut digest)?;
-
-        format!("{:x}", digest.finalize())
-    };
-
-    let mut checksum = String::new();
-    reader.take(32).read_to_string(&mut checksum)?;
-
-    Ok(calculated.eq_ignore_ascii_case(&checksum))
+        Ok(())
+    })
 }
 
 enum MappedEntry<'a> {
@@ -235,26 +528,31 @@ fn map_arguments_with_pit<'a>(
         match source {
             FileArgument::File { name, file } => {
                 let entry = pit
-                    .entries
-                    .iter()
-                    .find(|e| e.partition_name.eq_ignore_ascii_case(name.as_bytes()))
+                    .find_by_name_or_identifier(name)
                     .ok_or_else(|| FlashError::PartitionNotFound(name.to_string()))?;
 
                 total_file_size += file.metadata()?.len();
                 mapped.push(MappedEntry::Partition { file, entry });
             }
-            FileArgument::Tar { file } => {
+            FileArgument::Tar { file, filter } => {
                 let mut entries = vec![];
 
                 let mut tar = tar::Archive::new(BufReader::new(File::open(file)?));
-                for entry in tar.entries()? {
+                for entry in tar.entries_with_seek()? {
                     let entry = entry?;
                     let path = entry.path()?;
-                    let pit_entry = pit
-                        .entries
-                        .iter()
-                        .find(|e| e.flash_filename.eq_ignore_ascii_case(&*entry.path_bytes()))
-                        .ok_or_else(|| FlashError::FlashNameNotFound(path.display().to_string()))?;
+                    let name = path.to_string_lossy();
+                    if !filter.includes(&name) {
+                        continue;
+                    }
+                    let pit_entry = match filter.partition(&name) {
+                        Some(partition) => pit
+                            .find_by_name_or_identifier(partition)
+                            .ok_or_else(|| FlashError::PartitionNotFound(partition.to_string()))?,
+                        None => pit
+                            .find_by_flash_filename(&name)
+                            .ok_or_else(|| FlashError::FlashNameNotFound(name.to_string()))?,
+                    };
 
                     total_file_size += entry.size();
                     entries.push((pit_entry, (entry.raw_file_position(), entry.size())));
@@ -269,11 +567,27 @@ fn map_arguments_with_pit<'a>(
 use std::fmt;
 
 #[derive(Debug)]
-enum FlashError {
+pub(super) enum FlashError {
     InvalidChecksum(String),
     InvalidFile(String),
     PartitionNotFound(String),
     FlashNameNotFound(String),
+    PitMismatch,
+    Interrupted,
+}
+
+impl FlashError {
+    pub(super) fn kind(&self) -> ErrorKind {
+        match self {
+            FlashError::InvalidChecksum(_) => ErrorKind::Checksum,
+            FlashError::InvalidFile(_) => ErrorKind::InvalidFile,
+            FlashError::PartitionNotFound(_) | FlashError::FlashNameNotFound(_) => {
+                ErrorKind::PartitionNotFound
+            }
+            FlashError::PitMismatch => ErrorKind::PitMismatch,
+            FlashError::Interrupted => ErrorKind::Interrupted,
+        }
+    }
 }
 
 impl std::error::Error for FlashError {}
@@ -285,6 +599,67 @@ impl fmt::Display for FlashError {
             FlashError::InvalidFile(name) => write!(f, r#"invalid file: "{}""#, name),
             FlashError::PartitionNotFound(name) => write!(f, r#"partition not found: "{}""#, name),
             FlashError::FlashNameNotFound(name) => write!(f, r#"flash name not found: "{}""#, name),
+            FlashError::PitMismatch => {
+                f.write_str("the PIT of the device differs from the expected PIT")
+            }
+            FlashError::Interrupted => f.write_str("interrupted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// PIT of a boot and a system partition, the names have stale bytes after the NUL.
+    fn pit(system_blocks: u32, stale: &[u8]) -> Vec<u8> {
+        let mut pit = vec![];
+        pit.extend_from_slice(&0x12349876u32.to_le_bytes());
+        pit.extend_from_slice(&2u32.to_le_bytes());
+        pit.extend_from_slice(b"COM_TAR2MSM8996\0");
+        pit.extend_from_slice(&[0; 4]);
+        for (id, name, blocks) in [(70, &b"BOOT"[..], 0x4000), (80, b"SYSTEM", system_blocks)] {
+            for field in [0, 2, id, 1, 0, 0x2000, blocks, 0, 0] {
+                pit.extend_from_slice(&field.to_le_bytes());
+            }
+            for name in [name, b"", b""] {
+                let mut buf = name.to_vec();
+                buf.push(0);
+                buf.extend_from_slice(stale);
+                buf.resize(32, 0);
+                pit.extend(buf);
+            }
+        }
+        pit
+    }
+
+    #[test]
+    fn compare_pit_entries() {
+        let expected = pit(0x8000, b"");
+        assert!(same_partitions(&expected, &expected).unwrap());
+
+        // dumped PITs are padded and keep old bytes after the names
+        let mut dumped = pit(0x8000, b"old");
+        dumped.resize(4096, 0);
+        assert!(same_partitions(&dumped, &expected).unwrap());
+
+        assert!(!same_partitions(&pit(0x9000, b""), &expected).unwrap());
+        assert!(same_partitions(&expected[..100], &expected).is_err());
+    }
+
+    #[test]
+    fn reject_missing_files() {
+        let missing = env::temp_dir().join(format!("wuotan-missing-{}.img", process::id()));
+        let files = [FileArgument::File {
+            name: "BOOT".into(),
+            file: missing.clone(),
+        }];
+        let e = check_files(&files, false).unwrap_err();
+        match e.downcast_ref::<FlashError>() {
+            Some(FlashError::InvalidFile(name)) => assert_eq!(*name, missing.display().to_string()),
+            _ => panic!("unexpected error {:?}", e),
         }
     }
 }
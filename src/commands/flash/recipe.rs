@@ -0,0 +1,247 @@
+//! Flash jobs described in a TOML file.
+//!
+//! ```toml
+//! pit = "device.pit"      # expected PIT of the device
+//! repartition = false     # flash `pit` before the files
+//! verify = true           # verify the checksum of tar.md5 files
+//! reboot = true
+//!
+//! [[source]]
+//! tar = "AP_G930F.tar.md5"
+//! include = ["*.img", "*.lz4"]
+//! exclude = ["userdata.img*"]
+//! # map tar entries to partitions instead of the flash filenames of the PIT
+//! partitions = { "vbmeta.img" = "VBMETA" }
+//!
+//! [[source]]
+//! file = "twrp.img"
+//! partition = "RECOVERY"
+//! ```
+//!
+//! Relative paths are resolved against the directory of the recipe.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::FileArgument;
+use crate::commands::wildcard_match;
+use crate::error::Error;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Recipe {
+    pub pit: Option<PathBuf>,
+    #[serde(default)]
+    pub repartition: bool,
+    pub verify: Option<bool>,
+    pub reboot: Option<bool>,
+    #[serde(default, rename = "source")]
+    sources: Vec<Source>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Source {
+    file: Option<PathBuf>,
+    partition: Option<String>,
+    tar: Option<PathBuf>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    partitions: HashMap<String, String>,
+}
+
+/// Selection and mapping of the entries of a tar file.
+#[derive(Debug, Default)]
+pub struct TarFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    partitions: HashMap<String, String>,
+}
+
+impl TarFilter {
+    /// Whether the entry is included and not excluded.
+    pub fn includes(&self, name: &str) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|p| wildcard_match(p, name));
+        included && !self.exclude.iter().any(|p| wildcard_match(p, name))
+    }
+
+    /// Partition the entry is mapped to.
+    pub fn partition(&self, name: &str) -> Option<&str> {
+        self.partitions.get(name).map(String::as_str)
+    }
+}
+
+impl Recipe {
+    pub fn load(path: &Path) -> Result<Recipe, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read recipe {}: {}", path.display(), e))?;
+        let mut recipe: Recipe =
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let paths = recipe
+            .sources
+            .iter_mut()
+            .flat_map(|s| [&mut s.file, &mut s.tar]);
+        for path in paths.chain([&mut recipe.pit]).flatten() {
+            *path = base.join(&*path);
+        }
+
+        if recipe.repartition && recipe.pit.is_none() {
+            return Err(format!("{}: repartition requires a pit file", path.display()).into());
+        }
+        Ok(recipe)
+    }
+
+    pub fn files(&self) -> Result<Vec<FileArgument>, Error> {
+        self.sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                let file = match source {
+                    Source {
+                        file: Some(file),
+                        partition: Some(name),
+                        tar: None,
+                        ..
+                    } if source.include.is_empty()
+                        && source.exclude.is_empty()
+                        && source.partitions.is_empty() =>
+                    {
+                        FileArgument::File {
+                            name: name.clone(),
+                            file: file.clone(),
+                        }
+                    }
+                    Source {
+                        file: None,
+                        partition: None,
+                        tar: Some(file),
+                        ..
+                    } => FileArgument::Tar {
+                        file: file.clone(),
+                        filter: TarFilter {
+                            include: source.include.clone(),
+                            exclude: source.exclude.clone(),
+                            partitions: source.partitions.clone(),
+                        },
+                    },
+                    _ => {
+                        let msg = format!(
+                            "recipe source #{}: expected either `file` and `partition` or `tar` with optional `include`, `exclude` and `partitions`",
+                            i + 1
+                        );
+                        return Err(msg.into());
+                    }
+                };
+                Ok(file)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Writes the recipe to a temporary directory and loads it.
+    fn load(name: &str, content: &str) -> Result<Recipe, Error> {
+        let dir = env::temp_dir().join(format!("wuotan-recipe-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("recipe.toml");
+        fs::write(&path, content).unwrap();
+        let recipe = Recipe::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        recipe
+    }
+
+    #[test]
+    fn parse_recipe() {
+        let recipe = load(
+            "parse",
+            r#"
+            pit = "device.pit"
+            verify = false
+
+            [[source]]
+            tar = "AP.tar.md5"
+            include = ["*.img", "*.lz4"]
+            exclude = ["userdata.img*"]
+            partitions = { "vbmeta.img" = "VBMETA" }
+
+            [[source]]
+            file = "/images/twrp.img"
+            partition = "RECOVERY"
+            "#,
+        )
+        .unwrap();
+        let dir = env::temp_dir().join(format!("wuotan-recipe-parse-{}", std::process::id()));
+        assert_eq!(recipe.pit, Some(dir.join("device.pit")));
+        assert!(!recipe.repartition);
+        assert_eq!(recipe.verify, Some(false));
+        assert_eq!(recipe.reboot, None);
+
+        let files = recipe.files().unwrap();
+        match &files[..] {
+            [FileArgument::Tar { file: tar, filter }, FileArgument::File { name, file }] => {
+                assert_eq!(*tar, dir.join("AP.tar.md5"));
+                assert!(filter.includes("boot.img"));
+                assert!(filter.includes("super.img.lz4"));
+                assert!(!filter.includes("userdata.img.lz4"));
+                assert!(!filter.includes("cm.bin"));
+                assert_eq!(filter.partition("vbmeta.img"), Some("VBMETA"));
+                assert_eq!(filter.partition("boot.img"), None);
+                assert_eq!(name, "RECOVERY");
+                assert_eq!(*file, Path::new("/images/twrp.img"));
+            }
+            _ => panic!("unexpected files"),
+        }
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        let e = load("unknown", "reboot = true\nformat = true\n").unwrap_err();
+        assert!(e.to_string().contains("unknown field `format`"), "{}", e);
+
+        let source = "[[source]]\nfile = \"boot.img\"\npartition = \"BOOT\"\nname = \"x\"\n";
+        let e = load("unknown-source", source).unwrap_err();
+        assert!(e.to_string().contains("unknown field `name`"), "{}", e);
+    }
+
+    #[test]
+    fn reject_invalid_sources() {
+        let e = load("repartition", "repartition = true\n").unwrap_err();
+        assert!(
+            e.to_string().ends_with("repartition requires a pit file"),
+            "{}",
+            e
+        );
+
+        for source in [
+            "file = \"boot.img\"",
+            "partition = \"BOOT\"",
+            "tar = \"AP.tar\"\nfile = \"boot.img\"\npartition = \"BOOT\"",
+            "file = \"boot.img\"\npartition = \"BOOT\"\ninclude = [\"*\"]",
+        ] {
+            let recipe = load("source", &format!("[[source]]\n{}\n", source)).unwrap();
+            let e = recipe.files().err().expect(source);
+            assert!(e.to_string().starts_with("recipe source #1"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn reject_missing_recipe() {
+        let path =
+            env::temp_dir().join(format!("wuotan-recipe-missing-{}.toml", std::process::id()));
+        let e = Recipe::load(&path).unwrap_err();
+        assert!(e.to_string().starts_with("failed to read recipe"), "{}", e);
+    }
+}
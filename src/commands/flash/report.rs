@@ -0,0 +1,269 @@
+//! Audit report of a flash written with `--report FILE`.
+//!
+//! The report is a JSON document with the identity of the device, the SHA-256
+//! hash of its PIT and for every partition the source, the size, the number and
+//! the SHA-256 hash of the bytes sent to the device. It's written after the flash,
+//! also if it failed.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::commands::error_kind;
+use crate::device::Device;
+use crate::error::{CliResult, Error, ErrorKind};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    device: Option<DeviceIdentity>,
+    /// Start of the flash in seconds since the Unix epoch.
+    started: u64,
+    /// Duration of the flash in seconds.
+    duration: f64,
+    pit_sha256: Option<String>,
+    repartitioned: bool,
+    partitions: Vec<PartitionReport>,
+    result: Outcome,
+    error: Option<String>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceIdentity {
+    bus: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    port_path: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PartitionReport {
+    partition: String,
+    source: String,
+    size: u64,
+    /// Bytes written to the device, less than `size` if the transfer didn't finish.
+    sent: u64,
+    sha256: Option<String>,
+    duration: f64,
+    result: Outcome,
+    #[serde(skip)]
+    start: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Interrupted,
+    Failed,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            device: None,
+            started,
+            duration: 0.0,
+            pit_sha256: None,
+            repartitioned: false,
+            partitions: vec![],
+            result: Outcome::Failed,
+            error: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn device(&mut self, device: &Device) -> Result<(), Error> {
+        let (vendor_id, product_id) = device.id()?;
+        let strings = device.strings().unwrap_or_default();
+        self.device = Some(DeviceIdentity {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id,
+            product_id,
+            port_path: device.port_path().ok(),
+            manufacturer: strings.manufacturer,
+            product: strings.product,
+            serial_number: strings.serial_number,
+        });
+        Ok(())
+    }
+
+    pub fn pit(&mut self, pit: &[u8], repartitioned: bool) {
+        self.pit_sha256 = Some(hex(&Sha256::digest(pit)));
+        self.repartitioned = repartitioned;
+    }
+
+    /// Records the start of a partition, failed until [`partition_done`](Self::partition_done).
+    pub fn partition_start(&mut self, partition: &str, source: &str, size: u64) {
+        self.partitions.push(PartitionReport {
+            partition: partition.to_string(),
+            source: source.to_string(),
+            size,
+            sent: 0,
+            sha256: None,
+            duration: 0.0,
+            result: Outcome::Failed,
+            start: Instant::now(),
+        });
+    }
+
+    /// Records the outcome of the last started partition with the hash of the bytes
+    /// sent, also if the transfer failed.
+    pub fn partition_done(&mut self, hashing: Hashing, result: Outcome) {
+        if let Some(partition) = self.partitions.last_mut() {
+            partition.sent = hashing.len;
+            partition.sha256 = Some(hashing.finish());
+            partition.duration = partition.start.elapsed().as_secs_f64();
+            partition.result = result;
+        }
+    }
+
+    /// Records the outcome of the flash and writes the report.
+    pub fn write(mut self, file: File, result: &CliResult) -> Result<(), Error> {
+        self.duration = self.start.elapsed().as_secs_f64();
+        (self.result, self.error) = match result {
+            Ok(()) => (Outcome::Ok, None),
+            Err(e) if error_kind(e) == ErrorKind::Interrupted => (Outcome::Interrupted, None),
+            Err(e) => (Outcome::Failed, Some(e.to_string())),
+        };
+        for partition in &mut self.partitions {
+            if partition.sha256.is_none() {
+                partition.duration = partition.start.elapsed().as_secs_f64();
+            }
+        }
+
+        let mut out = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut out, &self)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// SHA-256 hash of the bytes sent to the device.
+pub struct Hashing {
+    digest: Sha256,
+    len: u64,
+}
+
+impl Hashing {
+    pub fn new() -> Self {
+        Self {
+            digest: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Adds bytes whose transfer to the device completed.
+    pub fn update(&mut self, data: &[u8]) {
+        self.digest.update(data);
+        self.len += data.len() as u64;
+    }
+
+    /// Returns the hex encoded hash of the bytes sent so far.
+    pub fn finish(self) -> String {
+        hex(&self.digest.finalize())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::commands::flash::FlashError;
+
+    /// Writes the report with the result and parses it again.
+    fn written(report: Report, name: &str, result: &CliResult) -> Value {
+        let path = env::temp_dir().join(format!(
+            "wuotan-report-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        report.write(File::create(&path).unwrap(), result).unwrap();
+        let value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        value
+    }
+
+    #[test]
+    fn write_report() {
+        let mut report = Report::new();
+        report.pit(b"PIT", true);
+        report.partition_start("BOOT", "AP.tar/boot.img", 3);
+        let mut hashing = Hashing::new();
+        hashing.update(b"ab");
+        hashing.update(b"c");
+        report.partition_done(hashing, Outcome::Ok);
+        report.partition_start("SYSTEM", "system.img", 10);
+
+        let report = written(report, "failed", &Err("transfer failed".into()));
+        assert_eq!(report["device"], Value::Null);
+        assert!(report["started"].as_u64().unwrap() > 0);
+        assert!(report["duration"].as_f64().unwrap() >= 0.0);
+        assert_eq!(
+            report["pit_sha256"],
+            "24c32f8b97218e6ae946901ea6e3cb2a151f94e7686a6ccd1d492cd76ad02a83"
+        );
+        assert_eq!(report["repartitioned"], true);
+        assert_eq!(report["result"], "failed");
+        assert_eq!(report["error"], "transfer failed");
+
+        let partitions = report["partitions"].as_array().unwrap();
+        assert_eq!(partitions.len(), 2);
+        let mut boot = partitions[0].clone();
+        assert!(boot["duration"].as_f64().unwrap() >= 0.0);
+        boot.as_object_mut().unwrap().remove("duration");
+        assert_eq!(
+            boot,
+            json!({
+                "partition": "BOOT",
+                "source": "AP.tar/boot.img",
+                "size": 3,
+                "sent": 3,
+                "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                "result": "ok",
+            })
+        );
+        // the partition whose transfer failed
+        let system = &partitions[1];
+        assert_eq!(system["partition"], "SYSTEM");
+        assert_eq!(system["sent"], 0);
+        assert_eq!(system["sha256"], Value::Null);
+        assert_eq!(system["result"], "failed");
+    }
+
+    #[test]
+    fn write_report_result() {
+        let report = written(Report::new(), "ok", &Ok(()));
+        assert_eq!(report["result"], "ok");
+        assert_eq!(report["error"], Value::Null);
+        assert_eq!(report["pit_sha256"], Value::Null);
+        assert_eq!(report["partitions"], json!([]));
+
+        let interrupted = Err(FlashError::Interrupted.into());
+        let report = written(Report::new(), "interrupted", &interrupted);
+        assert_eq!(report["result"], "interrupted");
+        assert_eq!(report["error"], Value::Null);
+    }
+}
@@ -0,0 +1,240 @@
+//! Verification of the MD5 checksum appended to `.tar.md5` files.
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+pub fn is_tar_md5(file: &Path) -> bool {
+    file.extension().map(|ext| ext == "md5").unwrap_or_default()
+}
+
+/// Size of the tar without the checksum line.
+fn tar_size(file: &Path) -> io::Result<u64> {
+    let file_size = file.metadata()?.len();
+    // tar_size = file_size - (checksum(32) + space(2) + basename + newline)
+    Ok(file
+        .file_stem()
+        .map(|name| 32 + 2 + name.len() as u64 + 1)
+        .and_then(|checksum_len| file_size.checked_sub(checksum_len))
+        .unwrap_or_default())
+}
+
+pub fn verify_tar_checksum(file: &Path) -> Result<bool, io::Error> {
+    let tar_size = tar_size(file)?;
+    let mut reader = BufReader::new(File::open(file)?);
+
+    let calculated = {
+        let mut tar = reader.by_ref().take(tar_size);
+        let mut digest = Md5::new();
+
+        io::copy(&mut tar, &mut digest)?;
+
+        format!("{:x}", digest.finalize())
+    };
+
+    let mut checksum = String::new();
+    reader.take(32).read_to_string(&mut checksum)?;
+
+    Ok(calculated.eq_ignore_ascii_case(&checksum))
+}
+
+/// Checksum of a tar computed while its entries are transferred.
+///
+/// The bytes between the transferred entries are read with a separate handle.
+pub struct TarChecksum {
+    file: BufReader<File>,
+    digest: Md5,
+    pos: u64,
+    tar_size: u64,
+    expected: String,
+    failed: bool,
+}
+
+impl TarChecksum {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let tar_size = tar_size(path)?;
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut expected = String::new();
+        file.seek(SeekFrom::Start(tar_size))?;
+        file.by_ref().take(32).read_to_string(&mut expected)?;
+
+        Ok(Self {
+            file,
+            digest: Md5::new(),
+            pos: 0,
+            tar_size,
+            expected,
+            failed: false,
+        })
+    }
+
+    /// Hashes the bytes up to `pos`, which must not be before the hashed bytes.
+    pub fn skip_to(&mut self, pos: u64) -> io::Result<()> {
+        if pos > self.pos {
+            self.file.seek(SeekFrom::Start(self.pos))?;
+            io::copy(
+                &mut self.file.by_ref().take(pos - self.pos),
+                &mut self.digest,
+            )?;
+            self.pos = pos;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the whole tar is hashed and verified.
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.tar_size
+    }
+
+    /// Returns whether the verification failed.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Hashes the rest of the tar and compares the checksum.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.skip_to(self.tar_size)?;
+        let calculated = format!("{:x}", self.digest.finalize_reset());
+        if !calculated.eq_ignore_ascii_case(&self.expected) {
+            self.failed = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tar checksum mismatch",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reader of a tar entry adding the bytes to the checksum of the tar.
+///
+/// The checksum is finished when the last entry is read completely, so the
+/// transfer fails before its last batch is completed if the checksum differs.
+pub struct Verifying<'a, R> {
+    inner: R,
+    checksum: Option<&'a mut TarChecksum>,
+    remaining: u64,
+    last: bool,
+}
+
+impl<'a, R> Verifying<'a, R> {
+    pub fn new(inner: R, checksum: Option<&'a mut TarChecksum>, size: u64, last: bool) -> Self {
+        Self {
+            inner,
+            checksum,
+            remaining: size,
+            last,
+        }
+    }
+}
+
+impl<R: Read> Read for Verifying<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(checksum) = self.checksum.as_mut() {
+            checksum.digest.update(&buf[..n]);
+            checksum.pos += n as u64;
+            self.remaining = self.remaining.saturating_sub(n as u64);
+            // also on the first read of an empty last entry
+            if self.remaining == 0 && self.last && !checksum.is_done() {
+                checksum.finish()?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a `.tar.md5` of the entries, with the checksum of `checksum_of`.
+    fn tar_md5(name: &str, entries: &[(&str, &[u8])], checksum_of: Option<&[u8]>) -> PathBuf {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        let mut data = builder.into_inner().unwrap();
+        let checksum = Md5::digest(checksum_of.unwrap_or(&data));
+
+        let name = format!("wuotan-{}-{}.tar", name, std::process::id());
+        data.extend(format!("{:x}  {}\n", checksum, name).into_bytes());
+        let path = env::temp_dir().join(name + ".md5");
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Reads the entries like the transfers of `flash --verify-while-flashing`.
+    fn stream(path: &Path) -> io::Result<TarChecksum> {
+        let mut entries = vec![];
+        let mut archive = tar::Archive::new(File::open(path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            entries.push((entry.raw_file_position(), entry.size()));
+        }
+
+        let mut checksum = TarChecksum::open(path)?;
+        let mut file = BufReader::new(File::open(path)?);
+        let count = entries.len();
+        for (i, (pos, size)) in entries.into_iter().enumerate() {
+            checksum.skip_to(pos)?;
+            file.seek(SeekFrom::Start(pos))?;
+            let reader = file.by_ref().take(size);
+            let mut reader = Verifying::new(reader, Some(&mut checksum), size, i + 1 == count);
+            let mut data = vec![];
+            reader.read_to_end(&mut data)?;
+            assert_eq!(data.len() as u64, size);
+        }
+        Ok(checksum)
+    }
+
+    #[test]
+    fn matching_checksum() {
+        let path = tar_md5(
+            "match",
+            &[("boot.img", &[1; 1000]), ("a.bin", &[2; 10])],
+            None,
+        );
+        assert!(verify_tar_checksum(&path).unwrap());
+
+        let checksum = stream(&path).unwrap();
+        assert!(checksum.is_done());
+        assert!(!checksum.is_failed());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mismatching_checksum() {
+        let entries = [("boot.img", &[1; 1000][..]), ("a.bin", &[2; 10])];
+        let path = tar_md5("mismatch", &entries, Some(b"other"));
+        assert!(!verify_tar_checksum(&path).unwrap());
+
+        let e = stream(&path).err().expect("checksum mismatch");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_last_entry() {
+        let entries = [("boot.img", &[1; 1000][..]), ("empty.bin", &[])];
+        let path = tar_md5("empty", &entries, None);
+        let checksum = stream(&path).unwrap();
+        assert!(checksum.is_done(), "checksum not finished");
+        assert!(!checksum.is_failed());
+        fs::remove_file(path).unwrap();
+
+        let path = tar_md5("empty-mismatch", &entries, Some(b"other"));
+        let e = stream(&path).err().expect("checksum mismatch");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+//! Command line of Heimdall translated to the wuotan commands.
+//!
+//! | Heimdall                                    | wuotan                                      |
+//! |---------------------------------------------|---------------------------------------------|
+//! | `heimdall detect`                           | `wuotan detect --fail-if-none`              |
+//! | `heimdall flash --BOOT boot.img --5 a.img`  | `wuotan flash -p BOOT boot.img -p 5 a.img --reboot` |
+//! | `heimdall print-pit`                        | `wuotan pit print --reboot`                 |
+//! | `heimdall print-pit --file FILE`            | `wuotan pit print -f FILE`                  |
+//! | `heimdall download-pit --output FILE`       | `wuotan pit download FILE --reboot`         |
+//!
+//! Like Heimdall, the actions using the device reboot it unless `--no-reboot` is
+//! given. The global options of wuotan, e.g. `--usb-id`, are passed on.
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use clap::{AppSettings, Arg, ArgMatches};
+
+use super::{App, CliResult, Error};
+
+const USAGE: &str = "\
+Usage: heimdall <action> <action arguments>
+
+Actions:
+  detect [--verbose] [--usb-log-level <none/error/warning/debug>]
+  download-pit --output <filename> [--verbose] [--no-reboot] [--stdout-errors]
+               [--usb-log-level <none/error/warning/debug>]
+  flash --<partition name> <filename> [--<partition identifier> <filename> ...]
+        [--verbose] [--no-reboot] [--stdout-errors] [--skip-size-check]
+        [--usb-log-level <none/error/warning/debug>]
+  help
+  print-pit [--file <filename>] [--verbose] [--no-reboot] [--stdout-errors]
+            [--usb-log-level <none/error/warning/debug>]
+  version";
+
+pub fn cli() -> App {
+    App::new("heimdall")
+        .about("run a Heimdall command line (also used if the binary is named \"heimdall\")")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::new("args")
+                .value_name("ARGS")
+                .multiple_values(true)
+                .allow_hyphen_values(true)
+                .allow_invalid_utf8(true)
+                .help("Heimdall action and its arguments"),
+        )
+}
+
+pub fn exec(args: &ArgMatches) -> CliResult {
+    let mut globals: Vec<OsString> = vec![];
+    for id in args.values_of_os("usb-id").into_iter().flatten() {
+        globals.extend(["--usb-id".into(), id.into()]);
+    }
+    if let Some(profile) = args.value_of_os("profile") {
+        globals.extend(["--profile".into(), profile.into()]);
+    }
+
+    let args = args.values_of_os("args").into_iter().flatten();
+    match translate(globals, args)? {
+        Some(args) => crate::exec(&crate::cli().get_matches_from(args)),
+        None => Ok(()),
+    }
+}
+
+/// Returns `true` if the program is called as `heimdall`.
+pub fn is_heimdall(program: &OsStr) -> bool {
+    Path::new(program).file_stem() == Some(OsStr::new("heimdall"))
+}
+
+/// Translates the Heimdall arguments to the arguments of wuotan after the global
+/// options, returns `None` if the action was handled already.
+fn translate<'a, I>(globals: Vec<OsString>, args: I) -> Result<Option<Vec<OsString>>, Error>
+where
+    I: IntoIterator<Item = &'a OsStr>,
+{
+    let mut args = args.into_iter();
+    let action = match args.next().map(|a| a.to_string_lossy()) {
+        Some(action) => action,
+        None => {
+            println!("{}", USAGE);
+            return Ok(None);
+        }
+    };
+
+    let mut options = Options::parse(args)?;
+    let mut out: Vec<OsString> = vec![env!("CARGO_PKG_NAME").into()];
+    out.extend(globals);
+    if let Some(level) = options.take("usb-log-level") {
+        match level.to_str() {
+            Some("none") => {}
+            Some("error") => out.extend(["--usb-log-level".into(), "error".into()]),
+            Some("warning") => out.extend(["--usb-log-level".into(), "warn".into()]),
+            Some("info") => out.extend(["--usb-log-level".into(), "info".into()]),
+            Some("debug") => out.extend(["--usb-log-level".into(), "debug".into()]),
+            _ => return Err(format!("invalid usb log level {:?}", level).into()),
+        }
+    }
+
+    match &*action {
+        "help" => {
+            println!("{}", USAGE);
+            return Ok(None);
+        }
+        "version" => {
+            println!("v{}", env!("CARGO_PKG_VERSION"));
+            return Ok(None);
+        }
+        "detect" => out.extend(["detect".into(), "--fail-if-none".into()]),
+        "print-pit" => {
+            out.extend(["pit".into(), "print".into()]);
+            let no_reboot = options.flag("no-reboot");
+            if let Some(file) = options.take("file") {
+                out.extend(["--file".into(), file]);
+            } else if !no_reboot {
+                out.push("--reboot".into());
+            }
+        }
+        "download-pit" => {
+            let output = options
+                .take("output")
+                .ok_or("download-pit: missing --output <filename>")?;
+            out.extend(["pit".into(), "download".into(), output]);
+            if !options.flag("no-reboot") {
+                out.push("--reboot".into());
+            }
+        }
+        "flash" => {
+            if options.flag("repartition") || options.take("pit").is_some() {
+                return Err(
+                    "flash: --pit and --repartition are not supported, use a recipe".into(),
+                );
+            }
+            if options.values.is_empty() {
+                return Err("flash: no partition to flash".into());
+            }
+            out.push("flash".into());
+            for (name, file) in options.values.drain(..) {
+                out.extend(["--part".into(), name.into(), file]);
+            }
+            if !options.flag("no-reboot") {
+                out.push("--reboot".into());
+            }
+        }
+        "close-pc-screen" => return Err("close-pc-screen is not supported".into()),
+        action => return Err(format!("unknown action \"{}\"\n\n{}", action, USAGE).into()),
+    }
+    if options.flag("resume") {
+        return Err("--resume is not supported".into());
+    }
+
+    if let Some((name, _)) = options.values.first() {
+        return Err(format!("{}: unknown argument --{}", action, name).into());
+    }
+    if let Some(flag) = options.flags.first() {
+        return Err(format!("{}: unknown argument --{}", action, flag).into());
+    }
+    Ok(Some(out))
+}
+
+/// Flags that are accepted by every action without an effect.
+const IGNORED_FLAGS: &[&str] = &["verbose", "stdout-errors", "skip-size-check"];
+
+/// Options of an action, in the order given.
+struct Options {
+    values: Vec<(String, OsString)>,
+    flags: Vec<String>,
+}
+
+impl Options {
+    fn parse<'a>(args: impl Iterator<Item = &'a OsStr>) -> Result<Self, Error> {
+        let mut args = args.peekable();
+        let mut values = vec![];
+        let mut flags = vec![];
+
+        while let Some(arg) = args.next() {
+            let arg = arg.to_string_lossy();
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument \"{}\"", arg))?;
+
+            if let Some((name, value)) = name.split_once('=') {
+                values.push((name.to_string(), value.into()));
+            } else if IGNORED_FLAGS.contains(&name) {
+                continue;
+            } else if ["no-reboot", "repartition", "resume"].contains(&name) {
+                flags.push(name.to_string());
+            } else if let Some(value) = args.next_if(|v| !v.to_string_lossy().starts_with("--")) {
+                values.push((name.to_string(), value.to_os_string()));
+            } else {
+                return Err(format!("missing value of --{}", name).into());
+            }
+        }
+        Ok(Self { values, flags })
+    }
+
+    /// Removes the value of an option.
+    fn take(&mut self, name: &str) -> Option<OsString> {
+        let idx = self.values.iter().position(|(n, _)| n == name)?;
+        Some(self.values.remove(idx).1)
+    }
+
+    /// Removes a flag, returns `true` if it was given.
+    fn flag(&mut self, name: &str) -> bool {
+        let len = self.flags.len();
+        self.flags.retain(|f| f != name);
+        self.flags.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(globals: &[&str], args: &[&str]) -> Result<Option<Vec<String>>, Error> {
+        let globals = globals.iter().map(OsString::from).collect();
+        let args = args.iter().map(OsStr::new);
+        let out = translate(globals, args)?;
+        Ok(out.map(|out| out.into_iter().map(|a| a.into_string().unwrap()).collect()))
+    }
+
+    fn translated(args: &[&str]) -> Vec<String> {
+        run(&[], args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn translate_detect() {
+        assert_eq!(
+            translated(&["detect"]),
+            ["wuotan", "detect", "--fail-if-none"]
+        );
+        assert_eq!(
+            translated(&["detect", "--verbose", "--usb-log-level", "warning"]),
+            [
+                "wuotan",
+                "--usb-log-level",
+                "warn",
+                "detect",
+                "--fail-if-none"
+            ]
+        );
+    }
+
+    #[test]
+    fn translate_flash() {
+        assert_eq!(
+            translated(&["flash", "--BOOT", "boot.img", "--5=a.img"]),
+            ["wuotan", "flash", "--part", "BOOT", "boot.img", "--part", "5", "a.img", "--reboot"]
+        );
+        assert_eq!(
+            translated(&[
+                "flash",
+                "--no-reboot",
+                "--BOOT",
+                "boot.img",
+                "--skip-size-check"
+            ]),
+            ["wuotan", "flash", "--part", "BOOT", "boot.img"]
+        );
+        assert!(run(&[], &["flash"]).is_err());
+        assert!(run(&[], &["flash", "--repartition", "--BOOT", "boot.img"]).is_err());
+        assert!(run(&[], &["flash", "--pit", "a.pit", "--BOOT", "boot.img"]).is_err());
+        assert!(run(&[], &["flash", "--BOOT", "boot.img", "--resume"]).is_err());
+        assert!(run(&[], &["flash", "--BOOT"]).is_err());
+    }
+
+    #[test]
+    fn translate_print_pit() {
+        assert_eq!(
+            translated(&["print-pit"]),
+            ["wuotan", "pit", "print", "--reboot"]
+        );
+        assert_eq!(
+            translated(&["print-pit", "--no-reboot", "--stdout-errors"]),
+            ["wuotan", "pit", "print"]
+        );
+        assert_eq!(
+            translated(&["print-pit", "--file", "a.pit"]),
+            ["wuotan", "pit", "print", "--file", "a.pit"]
+        );
+    }
+
+    #[test]
+    fn translate_download_pit() {
+        assert_eq!(
+            translated(&["download-pit", "--output", "a.pit"]),
+            ["wuotan", "pit", "download", "a.pit", "--reboot"]
+        );
+        assert_eq!(
+            translated(&["download-pit", "--output", "a.pit", "--no-reboot"]),
+            ["wuotan", "pit", "download", "a.pit"]
+        );
+        assert!(run(&[], &["download-pit"]).is_err());
+        assert!(run(
+            &[],
+            &["download-pit", "--output", "a.pit", "--file", "b.pit"]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn translate_other_actions() {
+        assert_eq!(run(&[], &[]).unwrap(), None);
+        assert_eq!(run(&[], &["help"]).unwrap(), None);
+        assert_eq!(run(&[], &["version"]).unwrap(), None);
+        assert!(run(&[], &["close-pc-screen"]).is_err());
+        assert!(run(&[], &["unknown"]).is_err());
+        assert!(run(&[], &["detect", "--usb-log-level", "trace"]).is_err());
+        assert!(run(&[], &["detect", "extra"]).is_err());
+    }
+
+    #[test]
+    fn pass_global_options() {
+        let globals = ["--usb-id", "04e8:685d", "--profile", "station-1"];
+        assert_eq!(
+            run(&globals, &["download-pit", "--output", "a.pit"])
+                .unwrap()
+                .unwrap(),
+            [
+                "wuotan",
+                "--usb-id",
+                "04e8:685d",
+                "--profile",
+                "station-1",
+                "pit",
+                "download",
+                "a.pit",
+                "--reboot"
+            ]
+        );
+
+        // the translated arguments are accepted
+        for action in [
+            &["detect"][..],
+            &["print-pit"],
+            &["print-pit", "--file", "a.pit"],
+            &["download-pit", "--output", "a.pit"],
+        ] {
+            let args = run(&globals, action).unwrap().unwrap();
+            assert!(
+                crate::cli().try_get_matches_from(args).is_ok(),
+                "{:?}",
+                action
+            );
+        }
+        let args = run(&globals, &["flash", "--BOOT", "boot.img"])
+            .unwrap()
+            .unwrap();
+        let matches = crate::cli().try_get_matches_from(args).unwrap();
+        let (cmd, args) = matches.subcommand().unwrap();
+        assert_eq!(cmd, "flash");
+        assert_eq!(
+            args.values_of("usb-id").unwrap().collect::<Vec<_>>(),
+            ["04e8:685d"]
+        );
+        assert_eq!(args.value_of("profile"), Some("station-1"));
+    }
+}
@@ -1,14 +1,16 @@
 use std::fs::File;
 use std::io::{BufReader, Cursor, Write};
 use std::path::PathBuf;
-use std::time::Duration;
 
 use clap::{AppSettings, Arg, ArgMatches};
 
-use super::{path_opt, App, AppExt, ArgMatchesExt, CliResult, Error};
-use crate::device::Handle;
-use crate::pit::Pit;
-use crate::proto;
+use super::{
+    cache_pit, opt, path_opt, wildcard_match, App, AppExt, ArgMatchesExt, CliResult, Error,
+};
+use crate::cache::PitCache;
+use crate::device::Device;
+use crate::events::{self, Event};
+use crate::pit::{BinaryType, DeviceType, Entry, Pit};
 
 pub fn cli() -> App {
     App::new("pit")
@@ -19,9 +21,33 @@ pub fn cli() -> App {
                 .arg(
                     path_opt("file", "read local PIT file")
                         .short('f')
-                        .value_name("FILE"),
+                        .value_name("FILE")
+                        .conflicts_with("pit-cache"),
                 )
-                .arg_select_device(),
+                .arg(opt("table", "print one row per entry"))
+                .arg(
+                    opt(
+                        "name",
+                        "only print the partitions matching a pattern with * and ?",
+                    )
+                    .value_name("PATTERN"),
+                )
+                .arg(
+                    opt(
+                        "type",
+                        "only print the partitions of a binary or device type",
+                    )
+                    .value_name("TYPE")
+                    .possible_values(["ap", "cp", "onenand", "file", "mmc", "all", "ufs"]),
+                )
+                .arg(
+                    opt("reboot", "reboot device after downloading the PIT")
+                        .conflicts_with_all(&["file", "pit-cache"]),
+                )
+                .arg_select_device()
+                .arg_pit_cache()
+                .arg_events()
+                .arg_capture(),
         )
         .subcommand(
             App::new("download")
@@ -33,7 +59,16 @@ pub fn cli() -> App {
                         .allow_invalid_utf8(true)
                         .help("path to the output file"),
                 )
-                .arg_select_device(),
+                .arg(opt("reboot", "reboot device after downloading the PIT"))
+                .arg_select_device()
+                .arg_events()
+                .arg_capture(),
+        )
+        .subcommand(
+            App::new("cache")
+                .about("manage the PITs cached from connected devices")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(App::new("list").about("list the cached PITs, the oldest first")),
         )
 }
 
@@ -41,11 +76,16 @@ pub fn exec(args: &ArgMatches) -> CliResult {
     match args.subcommand() {
         Some(("download", args)) => download(args),
         Some(("print", args)) => print(args),
+        Some(("cache", args)) => match args.subcommand() {
+            Some(("list", _)) => list_cache(),
+            _ => unreachable!(),
+        },
         _ => unreachable!(),
     }
 }
 
 fn download(args: &ArgMatches) -> CliResult {
+    events::init(args)?;
     let output = args.value_of_os("output").expect("argument is required");
     let output = PathBuf::from(output);
 
@@ -53,81 +93,106 @@ fn download(args: &ArgMatches) -> CliResult {
         return Err("output file already exists".into());
     }
 
-    if let Some(device) = args.selected_device()? {
-        let mut handle = device.open(Duration::from_secs(3))?;
-        handle.claim()?;
-        handle.reset()?;
-
-        let pit = download_pit(&handle)?;
+    let device = args.selected_device()?;
+    let pit = download_pit(args, &device)?;
 
-        handle.release()?;
+    let mut output = File::create(output)?;
+    output.write_all(&pit)?;
 
-        let mut output = File::create(output)?;
-        output.write_all(&pit)?;
-
-        println!("PIT download successful");
-    }
+    status!("PIT download successful");
     Ok(())
 }
 
 fn print(args: &ArgMatches) -> CliResult {
+    events::init(args)?;
     if args.is_present("file") {
         let input = args.value_of_os("file").unwrap();
         let mut input = BufReader::new(File::open(input)?);
         let pit = Pit::from_read(&mut input)?;
-        print_pit(&pit);
-    } else if let Some(device) = args.selected_device()? {
-        let mut handle = device.open(Duration::from_secs(3))?;
-        handle.claim()?;
-        handle.reset()?;
-
-        let pit = download_pit(&handle)?;
-
-        handle.release()?;
+        print_pit(args, &pit);
+    } else if let Some(pit) = args.cached_pit()? {
+        let pit = Pit::from_read(&mut Cursor::new(pit))?;
+        print_pit(args, &pit);
+    } else {
+        let device = args.selected_device()?;
+        let pit = download_pit(args, &device)?;
 
         let mut buf = Cursor::new(pit);
         let pit = Pit::from_read(&mut buf)?;
-        print_pit(&pit);
+        print_pit(args, &pit);
     }
     Ok(())
 }
 
-fn download_pit(handle: &Handle) -> Result<Vec<u8>, Error> {
-    proto::handshake(handle)?;
-    proto::begin_session(handle)?;
-
-    let data = proto::receive_pit(handle)?;
-
-    proto::end_session(handle)?;
+fn download_pit(args: &ArgMatches, device: &Device) -> Result<Vec<u8>, Error> {
+    let mut session = args.open_session(device)?;
+    let data = session.receive_pit()?;
+    events::emit(Event::PitReceived { size: data.len() });
+    if args.is_present("reboot") {
+        session.reboot()?;
+        events::emit(Event::SessionEnded);
+        events::emit(Event::Rebooting);
+        status!("Rebooting...");
+    } else {
+        session.finish()?;
+        events::emit(Event::SessionEnded);
+    }
+    cache_pit(&data);
     Ok(data)
 }
 
-fn print_pit(pit: &Pit) {
+fn list_cache() -> CliResult {
+    let cache = PitCache::open().ok_or("no cache directory")?;
+    for cached in cache.list()? {
+        let pit = Pit::from_read(BufReader::new(File::open(&cached.path)?))?;
+        println!("{}  {} entries", cached.fingerprint, pit.entries.len());
+    }
+    Ok(())
+}
+
+fn print_pit(args: &ArgMatches, pit: &Pit) {
     use crate::pit::{Attributes, UpdateAttributes};
 
-    println!("Entry Count: {}", pit.entries.len());
-    println!("Unknown 1: {}", pit.unknown1);
-    println!("Unknown 2: {}", pit.unknown2);
-    println!("Unknown 3: {}", pit.unknown3);
-    println!("Unknown 4: {}", pit.unknown4);
-    println!("Unknown 5: {}", pit.unknown5);
-    println!("Unknown 6: {}", pit.unknown6);
-    println!("Unknown 7: {}", pit.unknown7);
-    println!("Unknown 8: {}\n", pit.unknown8);
-
-    for (i, e) in pit.entries.iter().enumerate() {
-        println!("--- Entry #{} ---", i);
-        println!(
+    let (name, ty) = (args.value_of("name"), args.value_of("type"));
+    let entries: Vec<_> = pit
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| {
+            name.is_none_or(|name| wildcard_match(name, &e.partition_name.to_string()))
+                && ty.is_none_or(|ty| is_type(e, ty))
+        })
+        .collect();
+    if args.is_present("table") {
+        for line in format_table(entries) {
+            status!("{}", line);
+        }
+        return;
+    }
+
+    if name.is_some() || ty.is_some() {
+        status!("Entry Count: {} (of {})", entries.len(), pit.entries.len());
+    } else {
+        status!("Entry Count: {}", pit.entries.len());
+    }
+    status!("Gang Name: {}", pit.gang_name);
+    status!("Project Name: {}", pit.project_name);
+    let reserved: Vec<_> = pit.reserved.iter().map(|b| format!("{:02X}", b)).collect();
+    status!("Reserved: {}\n", reserved.join(" "));
+
+    for (i, e) in entries {
+        status!("--- Entry #{} ---", i);
+        status!(
             "Binary Type: {} ({})",
             e.binary_type.as_u32(),
             e.binary_type
         );
-        println!(
+        status!(
             "Device Type: {} ({})",
             e.device_type.as_u32(),
             e.device_type
         );
-        println!("Identifier: {}", e.identifier);
+        status!("Identifier: {}", e.identifier);
         let mut attr_s = String::new();
         if e.attributes.contains(Attributes::STL) {
             attr_s.push_str("STL ");
@@ -137,7 +202,7 @@ fn print_pit(pit: &Pit) {
         } else {
             attr_s.push_str("Read-Only");
         }
-        println!("Attributes: {:08b} ({})", e.attributes.bits(), attr_s);
+        status!("Attributes: {:08b} ({})", e.attributes.bits(), attr_s);
         let mut attr_s = String::new();
         if e.update_attributes.contains(UpdateAttributes::FOTA) {
             attr_s.push_str("FOTA");
@@ -149,17 +214,170 @@ fn print_pit(pit: &Pit) {
                 attr_s.push_str(", Secure");
             }
         }
-        println!(
+        status!(
             "Update Attributes: {:08b} ({})",
             e.attributes.bits(),
             attr_s
         );
-        println!("Partition Block Size/Offset: {}", e.blocksize_or_offset);
-        println!("Partition Block Count: {}", e.block_count);
-        println!("File Offset (Obsolete): {}", e.file_offset);
-        println!("File Size (Obsolete): {}", e.file_size);
-        println!("Partition Name: {}", e.partition_name);
-        println!("Flash Name: {}", e.flash_filename);
-        println!("FOTA Name: {}\n", e.fota_filename);
+        status!("Partition Block Size/Offset: {}", e.blocksize_or_offset);
+        status!("Partition Block Count: {}", e.block_count);
+        if let Some(block_size) = e.block_size() {
+            status!("Partition Block Size: {} bytes", block_size);
+        }
+        status!("File Offset (Obsolete): {}", e.file_offset);
+        status!("File Size (Obsolete): {}", e.file_size);
+        status!("Partition Name: {}", e.partition_name);
+        status!("Flash Name: {}", e.flash_filename);
+        status!("FOTA Name: {}\n", e.fota_filename);
+    }
+}
+
+/// Returns `true` if the entry is of the binary or device type given with `--type`.
+fn is_type(entry: &Entry, ty: &str) -> bool {
+    match ty {
+        "ap" => entry.binary_type == BinaryType::ApplicationProcessor,
+        "cp" => entry.binary_type == BinaryType::CommunicationProcessor,
+        "onenand" => entry.device_type == DeviceType::OneNAND,
+        "file" => entry.device_type == DeviceType::File,
+        "mmc" => entry.device_type == DeviceType::MMC,
+        "all" => entry.device_type == DeviceType::All,
+        "ufs" => entry.device_type == DeviceType::UFS,
+        _ => false,
+    }
+}
+
+/// Formats one row per entry, offsets and sizes are in bytes.
+fn format_table(entries: Vec<(usize, &Entry)>) -> Vec<String> {
+    use crate::pit::{Attributes, UpdateAttributes};
+
+    let header = [
+        "#",
+        "NAME",
+        "ID",
+        "TYPE",
+        "DEVICE",
+        "OFFSET",
+        "SIZE",
+        "ATTRIBUTES",
+        "FLASH NAME",
+    ];
+    let rows: Vec<[String; 9]> = entries
+        .into_iter()
+        .map(|(i, e)| {
+            let mut attributes = vec![if e.attributes.contains(Attributes::WRITE) {
+                "RW"
+            } else {
+                "RO"
+            }];
+            if e.attributes.contains(Attributes::STL) {
+                attributes.push("STL");
+            }
+            if e.update_attributes.contains(UpdateAttributes::FOTA) {
+                attributes.push("FOTA");
+            }
+            if e.update_attributes.contains(UpdateAttributes::SECURE) {
+                attributes.push("Secure");
+            }
+            let bytes = |n: Option<u64>| n.map_or_else(|| "-".to_string(), |n| n.to_string());
+            [
+                i.to_string(),
+                e.partition_name.to_string(),
+                e.identifier.to_string(),
+                e.binary_type.to_string(),
+                e.device_type.to_string(),
+                bytes(e.offset()),
+                bytes(e.size()),
+                attributes.join(" "),
+                e.flash_filename.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    // numbers are aligned right, text left
+    let numeric = [true, false, true, false, false, true, true, false, false];
+    let format_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .zip(numeric)
+            .map(|((cell, width), numeric)| match numeric {
+                true => format!("{:>1$}", cell, width),
+                false => format!("{:<1$}", cell, width),
+            })
+            .collect();
+        line.join("  ").trim_end().to_string()
+    };
+
+    let mut lines = vec![format_row(&header)];
+    for row in &rows {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        lines.push(format_row(&cells));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(device_type: u32, start_or_block_size: u32, block_count: u32, name: &str) -> Entry {
+        let mut data = vec![];
+        let fields = [
+            0,
+            device_type,
+            7,
+            1,
+            0,
+            start_or_block_size,
+            block_count,
+            0,
+            0,
+        ];
+        for field in fields {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for s in [name, "", ""] {
+            let mut name = s.as_bytes().to_vec();
+            name.resize(32, 0);
+            data.extend(name);
+        }
+        Entry::from_read(&data[..]).unwrap()
+    }
+
+    #[test]
+    fn table_offset_and_size() {
+        let entries = [
+            entry(2, 0x2000, 16, "BOOT"),
+            entry(8, 6, 0x100000, "SUPER"),
+            entry(0, 0x40000, 4, "NAND"),
+            entry(1, 0, 0, "FAT"),
+        ];
+        let lines = format_table(entries.iter().enumerate().collect());
+        assert_eq!(
+            lines,
+            [
+                "#  NAME   ID  TYPE  DEVICE     OFFSET        SIZE  ATTRIBUTES  FLASH NAME",
+                "0  BOOT    7  AP    MMC       4194304        8192  RW",
+                "1  SUPER   7  AP    UFS         24576  4294967296  RW",
+                "2  NAND    7  AP    OneNAND         -     1048576  RW",
+                "3  FAT     7  AP    File/FAT        -           -  RW",
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_by_type() {
+        let ufs = entry(8, 0, 1, "SUPER");
+        assert!(is_type(&ufs, "ap"));
+        assert!(is_type(&ufs, "ufs"));
+        assert!(!is_type(&ufs, "cp"));
+        assert!(!is_type(&ufs, "mmc"));
+        assert!(!is_type(&ufs, "unknown"));
     }
 }
@@ -1,30 +1,23 @@
-use std::time::Duration;
-
 use clap::ArgMatches;
 
-use crate::proto;
-
 use super::{App, AppExt, ArgMatchesExt, CliResult};
+use crate::events::{self, Event};
 
 pub fn cli() -> App {
     App::new("reboot")
         .about("reboot a connected device")
         .arg_select_device()
+        .arg_events()
+        .arg_capture()
 }
 
 pub fn exec(args: &ArgMatches) -> CliResult {
-    if let Some(device) = args.selected_device()? {
-        let mut handle = device.open(Duration::from_secs(3))?;
-        handle.claim()?;
-        handle.reset()?;
-
-        proto::handshake(&handle)?;
-        proto::reboot(&handle)?;
-
-        handle.release()?;
+    events::init(args)?;
+    let device = args.selected_device()?;
+    args.connect(&device)?.reboot()?;
+    events::emit(Event::Rebooting);
 
-        println!("Rebooting...");
-    }
+    status!("Rebooting...");
 
     Ok(())
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::device::DeviceMatch;
+use crate::error::Error;
+
+/// Name of the project-local configuration file in the current directory.
+const LOCAL_CONFIG: &str = "wuotan.toml";
+
+/// User configuration loaded from `$XDG_CONFIG_HOME/wuotan/config.toml`
+/// (or `~/.config/wuotan/config.toml`) and `wuotan.toml` in the current directory.
+///
+/// The top-level settings are the defaults, named profiles are selected with
+/// `--profile`. Values of the local file override the user file, values of the
+/// profile override the defaults and command line arguments override everything.
+///
+/// ```toml
+/// # additional download mode devices: "VID:PID" or "VID:PID:CLASS"
+/// usb-ids = ["04e8:6601", "04e8:1234:0a"]
+/// timeout = 5
+///
+/// [profiles.station-1]
+/// device = "3:68"
+/// no-verify = true
+/// reboot = true
+/// usb-log-level = "warn"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(default)]
+    usb_ids: Vec<String>,
+    #[serde(flatten)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: HashMap<String, Settings>,
+    /// Keys not taken by the flattened defaults, serde ignores `deny_unknown_fields`
+    /// of flattened structs, so they are rejected after parsing.
+    #[serde(flatten)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+/// Settings of the defaults or a profile.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    /// Device selector `BUS:ADDR`.
+    pub device: Option<String>,
+    pub no_verify: Option<bool>,
+    pub reboot: Option<bool>,
+    pub usb_log_level: Option<String>,
+    /// Timeout of the USB transfers in seconds.
+    pub timeout: Option<u64>,
+}
+
+impl Settings {
+    /// Overrides the values with the values set in `other`.
+    fn merge(&mut self, other: &Settings) {
+        fn set<T: Clone>(value: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                value.clone_from(other);
+            }
+        }
+        set(&mut self.device, &other.device);
+        set(&mut self.no_verify, &other.no_verify);
+        set(&mut self.reboot, &other.reboot);
+        set(&mut self.usb_log_level, &other.usb_log_level);
+        set(&mut self.timeout, &other.timeout);
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+impl Config {
+    /// Returns the merged user and project-local configuration, loaded once.
+    pub fn get() -> Result<&'static Config, Error> {
+        static CONFIG: OnceLock<Config> = OnceLock::new();
+
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let mut config = match config_dir() {
+            Some(dir) => Config::load(&dir.join("wuotan").join("config.toml"))?,
+            None => Config::default(),
+        };
+        config.merge(Config::load(Path::new(LOCAL_CONFIG))?);
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    fn load(path: &Path) -> Result<Config, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+        tracing::debug!("loading config: {}", path.display());
+
+        let config: Config =
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if let Some(key) = config.unknown.keys().min() {
+            return Err(format!("{}: unknown field `{}`", path.display(), key).into());
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Config) {
+        self.usb_ids.extend(other.usb_ids);
+        self.defaults.merge(&other.defaults);
+        for (name, settings) in other.profiles {
+            self.profiles.entry(name).or_default().merge(&settings);
+        }
+    }
+
+    pub fn usb_ids(&self) -> Result<Vec<DeviceMatch>, Error> {
+        self.usb_ids
+            .iter()
+            .map(|s| {
+                s.parse()
+                    .map_err(|e| format!(r#"invalid usb id in config "{}": {}"#, s, e).into())
+            })
+            .collect()
+    }
+
+    /// Returns the defaults merged with the given profile.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings, Error> {
+        let mut settings = self.defaults.clone();
+        if let Some(name) = profile {
+            let profile = self
+                .profiles
+                .get(name)
+                .ok_or_else(|| format!(r#"unknown profile "{}""#, name))?;
+            settings.merge(profile);
+        }
+        Ok(settings)
+    }
+}
+
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    #[test]
+    fn parse_settings() {
+        let config = parse(
+            r#"
+            usb-ids = ["04e8:1234"]
+            no-verify = true
+            timeout = 5
+
+            [profiles.station-1]
+            device = "3:68"
+            no-verify = false
+            "#,
+        )
+        .unwrap();
+        assert!(config.unknown.is_empty());
+        assert_eq!(config.usb_ids, ["04e8:1234"]);
+
+        let defaults = config.settings(None).unwrap();
+        assert_eq!(defaults.no_verify, Some(true));
+        assert_eq!(defaults.timeout(), Some(Duration::from_secs(5)));
+
+        let profile = config.settings(Some("station-1")).unwrap();
+        assert_eq!(profile.device.as_deref(), Some("3:68"));
+        assert_eq!(profile.no_verify, Some(false));
+        assert_eq!(profile.timeout, Some(5));
+    }
+
+    #[test]
+    fn reject_unknown_top_level_keys() {
+        let path = env::temp_dir().join(format!("wuotan-config-{}.toml", std::process::id()));
+        fs::write(&path, "timeout = 5\nno-verfy = true\n").unwrap();
+        let result = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+        let e = result.unwrap_err().to_string();
+        assert!(e.ends_with("unknown field `no-verfy`"), "{}", e);
+    }
+
+    #[test]
+    fn reject_unknown_profile_keys() {
+        assert!(parse("[profiles.x]\nno-verfy = true").is_err());
+    }
+}
@@ -1,20 +1,153 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
 use std::{io, time::Duration, vec::IntoIter};
 
-use rusb::constants::LIBUSB_CLASS_DATA;
+use rusb::constants::{LIBUSB_CLASS_DATA, LIBUSB_CLASS_IMAGE, LIBUSB_CLASS_VENDOR_SPEC};
 use rusb::{Error, UsbContext};
 
-const VENDOR_ID: u16 = 0x04E8;
-const PRODUCT_IDS: [u16; 3] = [0x6601, 0x685D, 0x68C3];
+use crate::proto::{self, Phase, Transport};
+use crate::record::{BulkTransfer, Capture};
+
+pub const SAMSUNG_VENDOR_ID: u16 = 0x04E8;
+
+/// Known vendor/product IDs of Samsung devices in download mode.
+pub const DOWNLOAD_MODE_IDS: [DeviceMatch; 3] = [
+    DeviceMatch::new(SAMSUNG_VENDOR_ID, 0x6601),
+    DeviceMatch::new(SAMSUNG_VENDOR_ID, 0x685D),
+    DeviceMatch::new(SAMSUNG_VENDOR_ID, 0x68C3),
+];
+
+/// Describes a device in download mode by its vendor/product ID and the class of the
+/// interface used for the bulk transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Interface class to look for. Defaults to the CDC data class.
+    pub interface_class: Option<u8>,
+}
+
+impl DeviceMatch {
+    pub const fn new(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            interface_class: None,
+        }
+    }
+
+    fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.vendor_id == vendor_id && self.product_id == product_id
+    }
+
+    fn interface_class(&self) -> u8 {
+        self.interface_class.unwrap_or(LIBUSB_CLASS_DATA)
+    }
+}
+
+impl fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)?;
+        if let Some(class) = self.interface_class {
+            write!(f, ":{:02x}", class)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `VID:PID` or `VID:PID:CLASS` with hexadecimal values (ex: "04e8:685d").
+impl FromStr for DeviceMatch {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let vendor_id = parts.next().ok_or("missing vendor id")?;
+        let product_id = parts.next().ok_or("missing product id")?;
+        let interface_class = parts.next();
+        if parts.next().is_some() {
+            return Err(r#"invalid usb id. expected: "VID:PID[:CLASS]""#);
+        }
+
+        let vendor_id = u16::from_str_radix(vendor_id, 16).map_err(|_| "invalid vendor id")?;
+        let product_id = u16::from_str_radix(product_id, 16).map_err(|_| "invalid product id")?;
+        let interface_class = interface_class
+            .map(|c| u8::from_str_radix(c, 16).map_err(|_| "invalid interface class"))
+            .transpose()?;
+
+        Ok(Self {
+            vendor_id,
+            product_id,
+            interface_class,
+        })
+    }
+}
 
 #[derive(Debug)]
-pub struct Devices(Vec<Device>);
+pub struct Devices {
+    devices: Vec<Device>,
+    others: Vec<OtherDevice>,
+}
+
+impl Devices {
+    /// Samsung devices which are connected but not in download mode.
+    pub fn others(&self) -> &[OtherDevice] {
+        &self.others
+    }
+}
 
 impl IntoIterator for Devices {
     type Item = Device;
     type IntoIter = IntoIter<Device>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.devices.into_iter()
+    }
+}
+
+bitflags::bitflags! {
+    /// USB functions exposed by a Samsung device in normal mode.
+    pub struct Functions: u8 {
+        const MTP = 0x01;
+        const ADB = 0x02;
+    }
+}
+
+impl fmt::Display for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.contains(Functions::MTP), self.contains(Functions::ADB)) {
+            (true, true) => f.write_str("MTP+ADB"),
+            (true, false) => f.write_str("MTP"),
+            (false, true) => f.write_str("ADB"),
+            (false, false) => f.write_str("unknown mode"),
+        }
+    }
+}
+
+/// A Samsung device which isn't in download mode and can't be used for flashing.
+#[derive(Debug)]
+pub struct OtherDevice {
+    device: rusb::Device<rusb::Context>,
+    functions: Functions,
+}
+
+impl OtherDevice {
+    pub fn id(&self) -> Result<(u16, u16), Error> {
+        let dd = self.device.device_descriptor()?;
+        Ok((dd.vendor_id(), dd.product_id()))
+    }
+
+    pub fn bus_number(&self) -> u8 {
+        self.device.bus_number()
+    }
+
+    pub fn address(&self) -> u8 {
+        self.device.address()
+    }
+
+    pub fn functions(&self) -> Functions {
+        self.functions
     }
 }
 
@@ -22,7 +155,6 @@ impl IntoIterator for Devices {
 pub struct Device {
     device: rusb::Device<rusb::Context>,
     iface_number: u8,
-    #[allow(dead_code)]
     alt_setting: u8,
     read_endpoint: u8,
     write_endpoint: u8,
@@ -39,6 +171,8 @@ impl Device {
             alt_setting: self.alt_setting,
             read_endpoint: self.read_endpoint,
             write_endpoint: self.write_endpoint,
+            captures: Mutex::new(vec![]),
+            phase: Mutex::new(Phase::Connected),
         })
     }
 
@@ -54,6 +188,61 @@ impl Device {
     pub fn address(&self) -> u8 {
         self.device.address()
     }
+
+    pub fn speed(&self) -> rusb::Speed {
+        self.device.speed()
+    }
+
+    /// Returns the port path in the sysfs notation (ex: "3-1.2").
+    pub fn port_path(&self) -> Result<String, Error> {
+        let ports = self.device.port_numbers()?;
+        let ports = ports.iter().map(u8::to_string).collect::<Vec<_>>();
+        Ok(format!("{}-{}", self.bus_number(), ports.join(".")))
+    }
+
+    pub fn interface_number(&self) -> u8 {
+        self.iface_number
+    }
+
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    pub fn read_endpoint(&self) -> u8 {
+        self.read_endpoint
+    }
+
+    pub fn write_endpoint(&self) -> u8 {
+        self.write_endpoint
+    }
+
+    /// Reads the manufacturer, product and serial number string descriptors.
+    ///
+    /// Missing or unreadable strings are returned as `None`.
+    pub fn strings(&self) -> Result<Strings, Error> {
+        let dd = self.device.device_descriptor()?;
+        let handle = self.device.open()?;
+
+        Ok(Strings {
+            manufacturer: handle.read_manufacturer_string_ascii(&dd).ok(),
+            product: handle.read_product_string_ascii(&dd).ok(),
+            serial_number: handle.read_serial_number_string_ascii(&dd).ok(),
+        })
+    }
+
+    /// Checks whether the interface can be claimed by temporarily claiming it.
+    pub fn check_claim(&self) -> Result<(), Error> {
+        let mut handle = self.device.open()?;
+        handle.claim_interface(self.iface_number)?;
+        handle.release_interface(self.iface_number)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Strings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
 }
 
 pub struct Handle {
@@ -64,6 +253,8 @@ pub struct Handle {
     alt_setting: u8,
     read_endpoint: u8,
     write_endpoint: u8,
+    captures: Mutex<Vec<Box<dyn Capture>>>,
+    phase: Mutex<Phase>,
 }
 
 impl Handle {
@@ -78,7 +269,9 @@ impl Handle {
         }
     }
 
+    /// Resets the device, a new handshake is needed afterwards.
     pub fn reset(&mut self) -> Result<(), Error> {
+        *self.phase.get_mut().expect("poisoned lock") = Phase::Connected;
         self.handle.reset()
     }
 
@@ -90,33 +283,84 @@ impl Handle {
         self.handle.release_interface(self.iface_number)
     }
 
+    /// Passes all following bulk transfers to `capture`.
+    pub fn add_capture<C: Capture + 'static>(&mut self, capture: C) {
+        self.captures
+            .get_mut()
+            .expect("poisoned lock")
+            .push(Box::new(capture));
+    }
+
     pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.handle.read_bulk(self.read_endpoint, buf, self.timeout)
+        let submitted = Instant::now();
+        let ret = self.handle.read_bulk(self.read_endpoint, buf, self.timeout);
+        self.capture(BulkTransfer {
+            endpoint: self.read_endpoint,
+            length: buf.len(),
+            submitted,
+            completed: Instant::now(),
+            result: ret.as_ref().map(|n| &buf[..*n]),
+        });
+        ret
     }
 
     pub fn write(&self, buf: &[u8]) -> Result<usize, Error> {
-        self.handle
-            .write_bulk(self.write_endpoint, buf, self.timeout)
+        let submitted = Instant::now();
+        let ret = self
+            .handle
+            .write_bulk(self.write_endpoint, buf, self.timeout);
+        self.capture(BulkTransfer {
+            endpoint: self.write_endpoint,
+            length: buf.len(),
+            submitted,
+            completed: Instant::now(),
+            result: ret.as_ref().map(|n| &buf[..*n]),
+        });
+        ret
+    }
+
+    fn capture(&self, transfer: BulkTransfer<'_>) {
+        let mut captures = self.captures.lock().expect("poisoned lock");
+        for capture in captures.iter_mut() {
+            if let Err(e) = capture.capture(&transfer) {
+                tracing::warn!("failed to capture transfer: {}", e);
+            }
+        }
+    }
+}
+
+impl Transport for Handle {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, proto::Error> {
+        Ok(Handle::read(self, buf)?)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, proto::Error> {
+        Ok(Handle::write(self, buf)?)
+    }
+
+    fn phase(&self) -> Phase {
+        *self.phase.lock().expect("poisoned lock")
+    }
+
+    fn set_phase(&self, phase: Phase) {
+        *self.phase.lock().expect("poisoned lock") = phase;
     }
 }
 
 impl io::Read for Handle {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.handle.read_bulk(self.read_endpoint, buf, self.timeout) {
+        match Handle::read(self, buf) {
             Ok(n) => Ok(n),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            Err(e) => Err(io::Error::other(e)),
         }
     }
 }
 
 impl io::Write for Handle {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self
-            .handle
-            .write_bulk(self.write_endpoint, buf, self.timeout)
-        {
+        match Handle::write(self, buf) {
             Ok(n) => Ok(n),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            Err(e) => Err(io::Error::other(e)),
         }
     }
 
@@ -125,7 +369,10 @@ impl io::Write for Handle {
     }
 }
 
-pub fn detect(log_level: Option<rusb::LogLevel>) -> Result<Devices, Error> {
+pub fn detect(
+    log_level: Option<rusb::LogLevel>,
+    matches: &[DeviceMatch],
+) -> Result<Devices, Error> {
     let mut context = rusb::Context::new()?;
     if let Some(level) = log_level {
         context.set_log_level(level);
@@ -133,23 +380,56 @@ pub fn detect(log_level: Option<rusb::LogLevel>) -> Result<Devices, Error> {
     let list = context.devices()?;
 
     let mut devices = vec![];
+    let mut others = vec![];
     for device in list.iter() {
-        let desc = device.device_descriptor()?;
+        // a device with an unreadable descriptor must not hide the others
+        let desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(e) => {
+                usb_debug!(device, "skipping device: {}", e);
+                continue;
+            }
+        };
 
-        if desc.vendor_id() != VENDOR_ID && !PRODUCT_IDS.iter().any(|id| *id == desc.product_id()) {
-            continue;
-        }
+        let found = matches
+            .iter()
+            .find(|m| m.matches(desc.vendor_id(), desc.product_id()));
+
+        let found = match found {
+            Some(found) => found,
+            None if desc.vendor_id() == SAMSUNG_VENDOR_ID => {
+                // a device that isn't in download mode must not hide the others
+                match probe_functions(&device) {
+                    Ok(functions) => {
+                        usb_debug!(device, "found Samsung device in {} mode", functions);
+                        others.push(OtherDevice { device, functions });
+                    }
+                    Err(e) => {
+                        usb_debug!(device, "skipping Samsung device: {}", e);
+                    }
+                }
+                continue;
+            }
+            None => continue,
+        };
 
         usb_debug!(device, "found Samsung device: {:?}", device);
 
-        let cd = device.config_descriptor(0)?;
+        let cd = match device.config_descriptor(0) {
+            Ok(cd) => cd,
+            Err(e) => {
+                usb_debug!(device, "skipping Samsung device: {}", e);
+                continue;
+            }
+        };
 
         let mut it = cd.interfaces().flat_map(|iface| iface.descriptors());
 
         let config = loop {
             match it.next() {
                 Some(iface)
-                    if iface.class_code() == LIBUSB_CLASS_DATA && iface.num_endpoints() == 2 =>
+                    if iface.class_code() == found.interface_class()
+                        && iface.num_endpoints() == 2 =>
                 {
                     let iface_number = iface.interface_number();
                     let alt_setting = iface.setting_number();
@@ -186,5 +466,44 @@ pub fn detect(log_level: Option<rusb::LogLevel>) -> Result<Devices, Error> {
         }
     }
 
-    Ok(Devices(devices))
+    Ok(Devices { devices, others })
+}
+
+/// Looks for the MTP and ADB interfaces of a device in normal mode.
+///
+/// Samsung devices usually expose MTP on a vendor specific interface instead of
+/// the still image class, it's recognized by its "MTP" interface string. The
+/// string can only be read if the device can be opened, otherwise the interface
+/// isn't reported.
+fn probe_functions(device: &rusb::Device<rusb::Context>) -> Result<Functions, Error> {
+    let cd = device.config_descriptor(0)?;
+    let mut handle = None;
+
+    let mut functions = Functions::empty();
+    for iface in cd.interfaces().flat_map(|iface| iface.descriptors()) {
+        match (
+            iface.class_code(),
+            iface.sub_class_code(),
+            iface.protocol_code(),
+        ) {
+            (LIBUSB_CLASS_IMAGE, 0x01, 0x01) => functions |= Functions::MTP,
+            (LIBUSB_CLASS_VENDOR_SPEC, 0x42, 0x01) => functions |= Functions::ADB,
+            (LIBUSB_CLASS_VENDOR_SPEC, _, _) => {
+                let index = match iface.description_string_index() {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let handle = match handle.get_or_insert_with(|| device.open()) {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+                let name = handle.read_string_descriptor_ascii(index);
+                if name.is_ok_and(|name| name.eq_ignore_ascii_case("mtp")) {
+                    functions |= Functions::MTP;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(functions)
 }
@@ -1,2 +1,53 @@
 pub type CliResult = Result<(), Error>;
 pub type Error = Box<dyn std::error::Error>;
+
+/// Classes of failures, reported as exit status and in the `error` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NoDevice,
+    PermissionDenied,
+    Handshake,
+    Protocol,
+    Usb,
+    Io,
+    Checksum,
+    InvalidFile,
+    PitMismatch,
+    PartitionNotFound,
+    Interrupted,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::NoDevice => "no_device",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::Handshake => "handshake",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Usb => "usb",
+            ErrorKind::Io => "io",
+            ErrorKind::Checksum => "checksum",
+            ErrorKind::InvalidFile => "invalid_file",
+            ErrorKind::PitMismatch => "pit_mismatch",
+            ErrorKind::PartitionNotFound => "partition_not_found",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Exit status of the process, `2` is used by the argument parser.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Io | ErrorKind::InvalidFile | ErrorKind::Other => 1,
+            ErrorKind::NoDevice => 3,
+            ErrorKind::PermissionDenied => 4,
+            ErrorKind::Handshake => 5,
+            ErrorKind::Protocol | ErrorKind::Usb => 6,
+            ErrorKind::Checksum => 7,
+            ErrorKind::PitMismatch => 8,
+            ErrorKind::PartitionNotFound => 9,
+            ErrorKind::Interrupted => 130,
+        }
+    }
+}
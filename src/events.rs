@@ -0,0 +1,184 @@
+//! JSON-lines event stream enabled with `--events json`.
+//!
+//! Every event is written as one JSON object on a line, to stdout or to the file
+//! descriptor given with `--events-fd`. The `event` field names the event:
+//!
+//! | event                | fields                                              |
+//! |----------------------|-----------------------------------------------------|
+//! | `device_opened`      | `bus`, `address`, `vendor_id`, `product_id`         |
+//! | `handshake`          |                                                     |
+//! | `session_started`    | `packet_size`                                       |
+//! | `pit_received`       | `size`                                              |
+//! | `pit_flashed`        | `size`                                              |
+//! | `total_size_sent`    | `size`                                              |
+//! | `partition_start`    | `partition`, `source`, `size`                       |
+//! | `partition_progress` | `partition`, `sent`, `size`                         |
+//! | `partition_done`     | `partition`, `size`                                 |
+//! | `session_ended`      |                                                     |
+//! | `rebooting`          |                                                     |
+//! | `error`              | `kind`, `message`                                   |
+//!
+//! Sizes are in bytes. `sent` counts the bytes of the partition whose bulk transfer
+//! to the device completed, the file is read ahead of it. If the events are written
+//! to stdout, the other output is written to stderr.
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Prints a status message to stdout, or to stderr if stdout is used for the events.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::events::is_stdout() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    DeviceOpened {
+        bus: u8,
+        address: u8,
+        vendor_id: u16,
+        product_id: u16,
+    },
+    Handshake,
+    SessionStarted {
+        packet_size: u32,
+    },
+    PitReceived {
+        size: usize,
+    },
+    PitFlashed {
+        size: usize,
+    },
+    TotalSizeSent {
+        size: u64,
+    },
+    PartitionStart {
+        partition: &'a str,
+        source: &'a str,
+        size: u64,
+    },
+    PartitionProgress {
+        partition: &'a str,
+        sent: u64,
+        size: u64,
+    },
+    PartitionDone {
+        partition: &'a str,
+        size: u64,
+    },
+    SessionEnded,
+    Rebooting,
+    Error {
+        kind: &'a str,
+        message: String,
+    },
+}
+
+struct Sink {
+    out: Mutex<Box<dyn Write + Send>>,
+    stdout: bool,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Enables the event stream if it's selected with `--events`.
+pub fn init(args: &ArgMatches) -> Result<(), Error> {
+    if args.value_of("events").is_none() {
+        return Ok(());
+    }
+    let sink = match args.value_of("events-fd") {
+        Some(fd) => Sink {
+            out: Mutex::new(open_fd(fd.parse()?)?),
+            stdout: false,
+        },
+        None => Sink {
+            out: Mutex::new(Box::new(io::stdout())),
+            stdout: true,
+        },
+    };
+    SINK.set(sink)
+        .map_err(|_| "the events are already initialized")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<Box<dyn Write + Send>, Error> {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    if fd < 0 {
+        return Err(format!("invalid file descriptor {}", fd).into());
+    }
+    // SAFETY: the descriptor is handed to the process by the caller for the events
+    // and isn't used otherwise.
+    Ok(Box::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<Box<dyn Write + Send>, Error> {
+    Err("--events-fd is only supported on unix".into())
+}
+
+/// Returns `true` if the events are written to stdout.
+pub fn is_stdout() -> bool {
+    SINK.get().is_some_and(|sink| sink.stdout)
+}
+
+pub fn emit(event: Event<'_>) {
+    let sink = match SINK.get() {
+        Some(sink) => sink,
+        None => return,
+    };
+    let mut out = sink.out.lock().unwrap_or_else(|e| e.into_inner());
+    let result = serde_json::to_writer(&mut *out, &event)
+        .map_err(io::Error::from)
+        .and_then(|_| out.write_all(b"\n"))
+        .and_then(|_| out.flush());
+    if let Err(e) = result {
+        tracing::warn!("failed to write event: {}", e);
+    }
+}
+
+/// Number of bytes between two `partition_progress` events.
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// Emits `partition_progress` events for the bytes sent to the device.
+pub struct Progress<'a> {
+    partition: &'a str,
+    sent: u64,
+    size: u64,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(partition: &'a str, size: u64) -> Self {
+        Self {
+            partition,
+            sent: 0,
+            size,
+        }
+    }
+
+    /// Adds `n` bytes whose transfer to the device completed.
+    pub fn sent(&mut self, n: usize) {
+        let before = self.sent;
+        self.sent += n as u64;
+        if n > 0 && (self.sent / PROGRESS_STEP != before / PROGRESS_STEP || self.sent == self.size)
+        {
+            emit(Event::PartitionProgress {
+                partition: self.partition,
+                sent: self.sent,
+                size: self.size,
+            });
+        }
+    }
+}
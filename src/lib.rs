@@ -0,0 +1,10 @@
+#[macro_use]
+mod macros;
+pub mod device;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod pcap;
+pub mod pit;
+pub mod proto;
+pub mod record;
+pub mod session;
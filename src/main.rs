@@ -1,28 +1,61 @@
+use std::env;
+use std::process::ExitCode;
+
 use clap::{crate_description, crate_name, crate_version};
-use clap::{App, AppSettings};
+use clap::{App, AppSettings, ArgMatches};
+
+use wuotan::{device, pit, proto, session};
 
 #[macro_use]
-mod macros;
+mod events;
+
+mod cache;
 mod commands;
-mod device;
+mod config;
 mod error;
-mod pit;
-mod proto;
 
 use commands::AppExt;
 use error::CliResult;
 
-fn main() -> CliResult {
+/// Exit status: see [`error::ErrorKind::exit_code`].
+fn main() -> ExitCode {
     tracing_subscriber::fmt::init();
 
-    let app = App::new(crate_name!())
+    let mut args = env::args_os().collect::<Vec<_>>();
+    if args
+        .first()
+        .is_some_and(|p| commands::heimdall::is_heimdall(p))
+    {
+        args.insert(1, "heimdall".into());
+    }
+
+    match exec(&cli().get_matches_from(args)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let kind = commands::error_kind(&e);
+            events::emit(events::Event::Error {
+                kind: kind.as_str(),
+                message: e.to_string(),
+            });
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(kind.exit_code())
+        }
+    }
+}
+
+fn cli() -> commands::App {
+    App::new(crate_name!())
         .about(crate_description!())
         .version(crate_version!())
         .setting(AppSettings::ArgRequiredElseHelp)
+        .arg_profile()
         .arg_usb_log_level()
-        .subcommands(commands::cli());
+        .arg_usb_id()
+        .subcommands(commands::cli())
+}
 
-    match app.get_matches().subcommand() {
+fn exec(matches: &ArgMatches) -> CliResult {
+    match matches.subcommand() {
         Some((cmd, args)) => {
             if let Some(cmd) = commands::get(cmd) {
                 cmd(args)?;
@@ -0,0 +1,324 @@
+//! Async versions of the device operations, available with the `async` feature.
+//!
+//! The libusb transfers stay blocking, they run on the blocking thread pool of the
+//! tokio runtime driving a [`Machine`] in the phase kept by the handle, so the
+//! executor threads are never blocked. Every operation can be cancelled with the [`CancellationToken`] of
+//! the handle or by dropping its future; the transfers stop before the next bulk
+//! transfer and the session fails.
+use std::io::Read;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::{self, JoinError};
+use tokio_util::sync::CancellationToken;
+
+use crate::device::{self, Device, DeviceMatch, Devices, Handle};
+use crate::proto::{self, Error, Event, FileTarget, Machine, Transport};
+
+/// Async version of [`device::detect`].
+pub async fn detect(
+    log_level: Option<rusb::LogLevel>,
+    matches: Vec<DeviceMatch>,
+) -> Result<Devices, Error> {
+    let task = task::spawn_blocking(move || device::detect(log_level, &matches));
+    Ok(task.await.map_err(join_error)??)
+}
+
+/// Async handle to a device in download mode.
+///
+/// Any [`Transport`] can be used, e.g. a [`Replay`](crate::record::Replay) in tests.
+pub struct AsyncHandle<T = Handle> {
+    handle: Arc<T>,
+    /// Held while an operation runs, the phase of the session is kept by the handle.
+    lock: Arc<Mutex<()>>,
+    token: CancellationToken,
+}
+
+impl AsyncHandle<Handle> {
+    /// Opens the device and claims its interface.
+    pub async fn open(device: Device, timeout: Duration) -> Result<Self, Error> {
+        let task = task::spawn_blocking(move || {
+            let mut handle = device.open(timeout)?;
+            handle.claim()?;
+            handle.reset()?;
+            Ok::<_, rusb::Error>(handle)
+        });
+        let handle = task.await.map_err(join_error)??;
+        Ok(Self::new(handle))
+    }
+
+    /// Releases the interface of the device.
+    pub async fn close(self) -> Result<(), Error> {
+        let mut handle = Arc::try_unwrap(self.handle).map_err(|_| Error::Sequence {
+            operation: "close",
+            state: "busy".into(),
+        })?;
+        let task = task::spawn_blocking(move || handle.release());
+        Ok(task.await.map_err(join_error)??)
+    }
+}
+
+impl<T: Transport + Send + Sync + 'static> AsyncHandle<T> {
+    /// Wraps a handle with a claimed interface.
+    pub fn new(handle: T) -> Self {
+        Self {
+            handle: Arc::new(handle),
+            lock: Arc::new(Mutex::new(())),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// Token cancelling the running and all further operations.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub async fn handshake(&self) -> Result<(), Error> {
+        self.run(Machine::handshake, None).await?;
+        Ok(())
+    }
+
+    pub async fn begin_session(&self) -> Result<u32, Error> {
+        match self.run(Machine::begin_session, None).await? {
+            Event::SessionBegun {
+                default_packet_size,
+            } => Ok(default_packet_size),
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn setup_file_part_size(&self, size: u32) -> Result<(), Error> {
+        self.run(move |m| m.setup_file_part_size(size), None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn receive_pit(&self) -> Result<Vec<u8>, Error> {
+        match self.run(Machine::receive_pit, None).await? {
+            Event::PitReceived(pit) => Ok(pit),
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn send_total_size(&self, size: u64) -> Result<(), Error> {
+        self.run(move |m| m.send_total_size(size), None).await?;
+        Ok(())
+    }
+
+    pub async fn file_transfer<R>(
+        &self,
+        target: FileTarget,
+        file: R,
+        file_size: u64,
+    ) -> Result<(), Error>
+    where
+        R: Read + Send + 'static,
+    {
+        let start = move |m: &mut Machine| m.file_transfer(&target, file_size);
+        self.run(start, Some(Box::new(file))).await?;
+        Ok(())
+    }
+
+    pub async fn end_session(&self) -> Result<(), Error> {
+        self.run(Machine::end_session, None).await?;
+        Ok(())
+    }
+
+    pub async fn reboot(&self) -> Result<(), Error> {
+        self.run(Machine::reboot, None).await?;
+        Ok(())
+    }
+
+    async fn run<F>(&self, start: F, mut file: Option<Box<dyn Read + Send>>) -> Result<Event, Error>
+    where
+        F: FnOnce(&mut Machine) -> Result<(), Error> + Send + 'static,
+    {
+        let handle = Arc::clone(&self.handle);
+        let lock = Arc::clone(&self.lock);
+        let token = self.token.child_token();
+        let _guard = token.clone().drop_guard();
+
+        let task = task::spawn_blocking(move || {
+            let _lock = lock.lock().expect("poisoned lock");
+            let file = file.as_mut().map(|f| f as &mut dyn Read);
+            proto::run_with(&*handle, start, |machine| {
+                proto::drive_cancellable(&*handle, machine, file, || token.is_cancelled())
+            })
+        });
+        task.await.map_err(join_error)?
+    }
+}
+
+fn join_error(e: JoinError) -> Error {
+    match e.try_into_panic() {
+        Ok(payload) => panic::resume_unwind(payload),
+        Err(_) => Error::Cancelled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::future::Future;
+    use std::io;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::proto::packet::{FileEnd, Request, Response, ResponseType};
+    use crate::proto::{Phase, FILE_PART_SIZE};
+    use crate::record::{BulkTransfer, Capture, Direction, Recorder, Replay};
+
+    const TARGET: FileTarget = FileTarget::ApplicationProcessor {
+        device_type: 2,
+        identifier: 81,
+    };
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(future)
+    }
+
+    /// Writes a recording of the transfers to a temporary file.
+    fn recording(name: &str, transfers: &[(Direction, Vec<u8>)]) -> PathBuf {
+        let path =
+            env::temp_dir().join(format!("wuotan-async-{}-{}.rec", name, std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        for (direction, data) in transfers {
+            let endpoint = match direction {
+                Direction::Out => 0x01,
+                Direction::In => 0x81,
+            };
+            let now = Instant::now();
+            let transfer = BulkTransfer {
+                endpoint,
+                length: data.len(),
+                submitted: now,
+                completed: now,
+                result: Ok(data),
+            };
+            recorder.capture(&transfer).unwrap();
+        }
+        path
+    }
+
+    fn request(request: Request) -> (Direction, Vec<u8>) {
+        (Direction::Out, request.encode())
+    }
+
+    fn response(kind: ResponseType, value: u32) -> (Direction, Vec<u8>) {
+        (Direction::In, Response { kind, value }.encode().to_vec())
+    }
+
+    /// Transfers up to the data of the first chunk of a 10 byte file.
+    fn transfer_start() -> Vec<(Direction, Vec<u8>)> {
+        vec![
+            (Direction::Out, b"ODIN".to_vec()),
+            (Direction::In, b"LOKE".to_vec()),
+            request(Request::BeginSession { version: 4 }),
+            response(ResponseType::Session, 0x100000),
+            request(Request::TotalBytes { size: 10 }),
+            response(ResponseType::Session, 0),
+            request(Request::FileFlash),
+            response(ResponseType::FileTransfer, 0),
+            request(Request::FilePart {
+                size: FILE_PART_SIZE,
+            }),
+            response(ResponseType::FileTransfer, 0),
+        ]
+    }
+
+    #[test]
+    fn replay_session() {
+        let mut chunk = b"0123456789".to_vec();
+        chunk.resize(FILE_PART_SIZE as usize, 0);
+        let mut transfers = transfer_start();
+        transfers.extend([
+            (Direction::Out, chunk),
+            response(ResponseType::FilePart, 0),
+            (Direction::Out, vec![]),
+            request(Request::FileEnd(FileEnd {
+                target: TARGET,
+                size: 10,
+                eof: true,
+            })),
+            response(ResponseType::FileTransfer, 0),
+            request(Request::EndSession),
+            response(ResponseType::EndSession, 0),
+            request(Request::Reboot),
+            response(ResponseType::EndSession, 0),
+        ]);
+        let path = recording("session", &transfers);
+        let handle = AsyncHandle::new(Replay::open(&path).unwrap());
+
+        block_on(async {
+            handle.handshake().await.unwrap();
+            assert_eq!(handle.begin_session().await.unwrap(), 0x100000);
+            handle.send_total_size(10).await.unwrap();
+            let file = io::Cursor::new(b"0123456789".to_vec());
+            handle.file_transfer(TARGET, file, 10).await.unwrap();
+            assert_eq!(handle.handle.phase(), Phase::TotalSizeSent);
+            // the phase after the transfer is kept for the next operations
+            let result = handle.receive_pit().await;
+            assert!(
+                matches!(result, Err(Error::Sequence { .. })),
+                "{:?}",
+                result
+            );
+            handle.end_session().await.unwrap();
+            handle.reboot().await.unwrap();
+        });
+        assert_eq!(handle.handle.phase(), Phase::Rebooting);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    /// File cancelling the operations of the handle once it's read.
+    struct Cancelling {
+        token: CancellationToken,
+        data: &'static [u8],
+    }
+
+    impl Read for Cancelling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.token.cancel();
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn cancel_transfer() {
+        let path = recording("cancel", &transfer_start());
+        let handle = AsyncHandle::new(Replay::open(&path).unwrap());
+
+        block_on(async {
+            handle.handshake().await.unwrap();
+            handle.begin_session().await.unwrap();
+            handle.send_total_size(10).await.unwrap();
+            let file = Cancelling {
+                token: handle.cancellation_token().clone(),
+                data: b"0123456789",
+            };
+            let result = handle.file_transfer(TARGET, file, 10).await;
+            assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+            assert_eq!(handle.handle.phase(), Phase::Failed);
+
+            let result = handle.end_session().await;
+            assert!(
+                matches!(result, Err(Error::Sequence { .. })),
+                "{:?}",
+                result
+            );
+        });
+
+        fs::remove_file(path).unwrap();
+    }
+}
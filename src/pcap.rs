@@ -0,0 +1,276 @@
+//! Export of the USB traffic as pcapng file.
+//!
+//! The transfers are written with the `LINKTYPE_USB_LINUX_MMAPPED` link type, each one
+//! as a submit and a complete event with synthesized usbmon URB headers, so the file
+//! can be inspected with Wireshark next to usbmon captures.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{WriteBytesExt, LE};
+
+use crate::record::{BulkTransfer, Capture, Direction};
+
+const BLOCK_TYPE_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_TYPE_IDB: u32 = 0x0000_0001;
+const BLOCK_TYPE_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+const URB_HEADER_LEN: usize = 64;
+const URB_TRANSFER_BULK: u8 = 3;
+
+const EINPROGRESS: i32 = 115;
+
+pub struct PcapngWriter {
+    out: BufWriter<File>,
+    bus_number: u8,
+    address: u8,
+    next_id: u64,
+    start: (Instant, SystemTime),
+}
+
+impl PcapngWriter {
+    pub fn create<P: AsRef<Path>>(path: P, bus_number: u8, address: u8) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        // Section Header Block
+        out.write_u32::<LE>(BLOCK_TYPE_SHB)?;
+        out.write_u32::<LE>(28)?;
+        out.write_u32::<LE>(BYTE_ORDER_MAGIC)?;
+        out.write_u16::<LE>(1)?; // major version
+        out.write_u16::<LE>(0)?; // minor version
+        out.write_i64::<LE>(-1)?; // section length not specified
+        out.write_u32::<LE>(28)?;
+
+        // Interface Description Block
+        out.write_u32::<LE>(BLOCK_TYPE_IDB)?;
+        out.write_u32::<LE>(20)?;
+        out.write_u16::<LE>(LINKTYPE_USB_LINUX_MMAPPED)?;
+        out.write_u16::<LE>(0)?; // reserved
+        out.write_u32::<LE>(0)?; // no snap length limit
+        out.write_u32::<LE>(20)?;
+
+        Ok(Self {
+            out,
+            bus_number,
+            address,
+            next_id: 1,
+            start: (Instant::now(), SystemTime::now()),
+        })
+    }
+
+    fn write_event(&mut self, event: &UrbEvent<'_>) -> io::Result<()> {
+        let data_len = event.data.len();
+        let packet_len = URB_HEADER_LEN + data_len;
+        let padding = (4 - packet_len % 4) % 4;
+        let block_len = 32 + packet_len + padding;
+
+        let (instant, system) = self.start;
+        let time = system + event.time.saturating_duration_since(instant);
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let micros = time.as_micros() as u64;
+
+        // Enhanced Packet Block
+        let out = &mut self.out;
+        out.write_u32::<LE>(BLOCK_TYPE_EPB)?;
+        out.write_u32::<LE>(block_len as u32)?;
+        out.write_u32::<LE>(0)?; // interface id
+        out.write_u32::<LE>((micros >> 32) as u32)?;
+        out.write_u32::<LE>(micros as u32)?;
+        out.write_u32::<LE>(packet_len as u32)?;
+        out.write_u32::<LE>(packet_len as u32)?;
+
+        // usbmon URB header
+        out.write_u64::<LE>(event.id)?;
+        out.write_u8(event.kind)?;
+        out.write_u8(URB_TRANSFER_BULK)?;
+        out.write_u8(event.endpoint)?;
+        out.write_u8(self.address)?;
+        out.write_u16::<LE>(u16::from(self.bus_number))?;
+        out.write_u8(b'-')?; // no setup packet
+        out.write_u8(event.data_flag)?;
+        out.write_i64::<LE>(time.as_secs() as i64)?;
+        out.write_i32::<LE>(time.subsec_micros() as i32)?;
+        out.write_i32::<LE>(event.status)?;
+        out.write_u32::<LE>(event.length)?;
+        out.write_u32::<LE>(data_len as u32)?;
+        out.write_all(&[0; 8])?; // setup
+        out.write_i32::<LE>(0)?; // interval
+        out.write_i32::<LE>(0)?; // start frame
+        out.write_u32::<LE>(0)?; // transfer flags
+        out.write_u32::<LE>(0)?; // number of iso descriptors
+
+        out.write_all(event.data)?;
+        out.write_all(&[0; 3][..padding])?;
+        out.write_u32::<LE>(block_len as u32)
+    }
+}
+
+impl Capture for PcapngWriter {
+    fn capture(&mut self, transfer: &BulkTransfer<'_>) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let data = transfer.result.unwrap_or_default();
+        let (status, actual_length) = match transfer.result {
+            Ok(data) => (0, data.len()),
+            Err(e) => (-errno(e), 0),
+        };
+
+        let (submit_data, complete_data) = match transfer.direction() {
+            Direction::Out => (data, &[][..]),
+            Direction::In => (&[][..], data),
+        };
+
+        self.write_event(&UrbEvent {
+            id,
+            kind: b'S',
+            endpoint: transfer.endpoint,
+            time: transfer.submitted,
+            status: -EINPROGRESS,
+            length: transfer.length as u32,
+            data_flag: if submit_data.is_empty() { b'<' } else { 0 },
+            data: submit_data,
+        })?;
+        self.write_event(&UrbEvent {
+            id,
+            kind: b'C',
+            endpoint: transfer.endpoint,
+            time: transfer.completed,
+            status,
+            length: actual_length as u32,
+            data_flag: if complete_data.is_empty() { b'>' } else { 0 },
+            data: complete_data,
+        })
+    }
+}
+
+struct UrbEvent<'a> {
+    id: u64,
+    kind: u8,
+    endpoint: u8,
+    time: Instant,
+    status: i32,
+    length: u32,
+    data_flag: u8,
+    data: &'a [u8],
+}
+
+/// Maps the libusb errors to the errno values reported by usbmon.
+fn errno(e: &rusb::Error) -> i32 {
+    match e {
+        rusb::Error::Timeout => 110,     // ETIMEDOUT
+        rusb::Error::Pipe => 32,         // EPIPE
+        rusb::Error::NoDevice => 19,     // ENODEV
+        rusb::Error::Overflow => 75,     // EOVERFLOW
+        rusb::Error::Interrupted => 4,   // EINTR
+        rusb::Error::Access => 13,       // EACCES
+        rusb::Error::NotSupported => 95, // EOPNOTSUPP
+        _ => 5,                          // EIO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use byteorder::{ByteOrder, LE};
+
+    use super::*;
+
+    /// Expected usbmon header, without the timestamp at bytes 16..28.
+    fn urb_header(
+        id: u64,
+        kind: u8,
+        endpoint: u8,
+        flag: u8,
+        status: i32,
+        len: [u32; 2],
+    ) -> Vec<u8> {
+        let mut header = vec![];
+        header.extend_from_slice(&id.to_le_bytes());
+        header.extend_from_slice(&[kind, URB_TRANSFER_BULK, endpoint, 68, 3, 0, b'-', flag]);
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&status.to_le_bytes());
+        header.extend_from_slice(&len[0].to_le_bytes());
+        header.extend_from_slice(&len[1].to_le_bytes());
+        header.extend_from_slice(&[0; 24]);
+        header
+    }
+
+    /// Splits the next block off `buf`, checking its type and both lengths.
+    fn block<'a>(buf: &mut &'a [u8], block_type: u32, len: usize) -> &'a [u8] {
+        assert_eq!(LE::read_u32(buf), block_type);
+        assert_eq!(LE::read_u32(&buf[4..]) as usize, len);
+        assert_eq!(LE::read_u32(&buf[len - 4..]) as usize, len);
+        let (block, rest) = buf.split_at(len);
+        *buf = rest;
+        block
+    }
+
+    /// Checks an enhanced packet block and returns its packet.
+    fn packet<'a>(buf: &mut &'a [u8], block_len: usize, data: &[u8]) -> &'a [u8] {
+        let epb = block(buf, BLOCK_TYPE_EPB, block_len);
+        let packet_len = URB_HEADER_LEN + data.len();
+        assert_eq!(LE::read_u32(&epb[8..]), 0); // interface id
+        assert_eq!(LE::read_u32(&epb[20..]) as usize, packet_len);
+        assert_eq!(LE::read_u32(&epb[24..]) as usize, packet_len);
+        let packet = &epb[28..28 + packet_len];
+        assert_eq!(&packet[URB_HEADER_LEN..], data);
+        assert!(epb[28 + packet_len..block_len - 4].iter().all(|&b| b == 0));
+        packet
+    }
+
+    fn assert_header(packet: &[u8], expected: Vec<u8>) {
+        assert_eq!(packet[..16], expected[..16]);
+        assert_eq!(packet[28..URB_HEADER_LEN], expected[28..]);
+    }
+
+    #[test]
+    fn write_bulk_transfers() {
+        let path = env::temp_dir().join(format!("wuotan-pcap-{}.pcapng", std::process::id()));
+        let mut writer = PcapngWriter::create(&path, 3, 68).unwrap();
+        let now = Instant::now();
+        for (endpoint, data) in [(0x01, &b"ODIN"[..]), (0x81, b"LOKE\x00")] {
+            writer
+                .capture(&BulkTransfer {
+                    endpoint,
+                    length: 8,
+                    submitted: now,
+                    completed: now,
+                    result: Ok(data),
+                })
+                .unwrap();
+        }
+        drop(writer);
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut buf = &data[..];
+        let shb = block(&mut buf, BLOCK_TYPE_SHB, 28);
+        assert_eq!(LE::read_u32(&shb[8..]), BYTE_ORDER_MAGIC);
+        assert_eq!(shb[12..16], [1, 0, 0, 0]);
+        assert_eq!(LE::read_i64(&shb[16..]), -1);
+        let idb = block(&mut buf, BLOCK_TYPE_IDB, 20);
+        assert_eq!(LE::read_u16(&idb[8..]), LINKTYPE_USB_LINUX_MMAPPED);
+
+        // the data of an out transfer is in the submit event
+        let submit = packet(&mut buf, 100, b"ODIN");
+        assert_header(submit, urb_header(1, b'S', 0x01, 0, -EINPROGRESS, [8, 4]));
+        let complete = packet(&mut buf, 96, b"");
+        assert_header(complete, urb_header(1, b'C', 0x01, b'>', 0, [4, 0]));
+
+        // the data of an in transfer is in the complete event, padded to 4 bytes
+        let submit = packet(&mut buf, 96, b"");
+        assert_header(
+            submit,
+            urb_header(2, b'S', 0x81, b'<', -EINPROGRESS, [8, 0]),
+        );
+        let complete = packet(&mut buf, 104, b"LOKE\x00");
+        assert_header(complete, urb_header(2, b'C', 0x81, 0, 0, [5, 5]));
+        assert!(buf.is_empty());
+    }
+}
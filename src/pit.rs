@@ -1,61 +1,190 @@
 use std::fmt;
-use std::io;
+use std::io::{self, Read};
 use std::ops::Deref;
 
-use byteorder::{ReadBytesExt, LE};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
 
 const PIT_SIGNATURE: u32 = 0x12349876;
 
+/// Size of the entries of the legacy layout.
+const ENTRY_SIZE: usize = 132;
+
+/// Largest entry size looked for in newer layouts.
+const MAX_ENTRY_SIZE: usize = 512;
+
 #[derive(Debug)]
 pub struct Pit {
     _signature: u32,
-    pub unknown1: u32,
-    pub unknown2: u32,
-    pub unknown3: u16,
-    pub unknown4: u16,
-    pub unknown5: u16,
-    pub unknown6: u16,
-    pub unknown7: u16,
-    pub unknown8: u16,
+    /// Marker of the PIT format, e.g. `COM_TAR2`.
+    pub gang_name: Name<8>,
+    /// Name of the project or chip the PIT belongs to, e.g. `MSM8996`.
+    pub project_name: Name<8>,
+    pub reserved: [u8; 4],
+    pub layout: Layout,
     pub entries: Vec<Entry>,
 }
 
+/// Layout of the entries of a PIT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Entries of 132 bytes.
+    Legacy,
+    /// Entries of newer PITs, which have more bytes after the fields of the legacy
+    /// layout, see [`Entry::extra`].
+    Extended { entry_size: usize },
+}
+
+impl Layout {
+    pub fn entry_size(&self) -> usize {
+        match self {
+            Layout::Legacy => ENTRY_SIZE,
+            Layout::Extended { entry_size } => *entry_size,
+        }
+    }
+
+    /// Detects the layout from the entries following the header.
+    ///
+    /// The PIT doesn't store the size of its entries and is often padded, so the size
+    /// is the distance to the second entry: 132 bytes if an entry follows the fields
+    /// of the first one, otherwise the next offset a valid entry is found at.
+    fn detect(entries: &[u8], count: usize) -> Layout {
+        let is_entry_at = |offset: usize| entries.get(offset..).is_some_and(is_entry);
+        if count < 2 || is_entry_at(ENTRY_SIZE) {
+            return Layout::Legacy;
+        }
+        (ENTRY_SIZE + 4..=MAX_ENTRY_SIZE)
+            .step_by(4)
+            .find(|&size| size * count <= entries.len() && is_entry_at(size))
+            .map_or(Layout::Legacy, |entry_size| Layout::Extended { entry_size })
+    }
+}
+
+/// Returns `true` if the data starts with an entry of a known binary type, a small
+/// device type and a printable partition name.
+fn is_entry(data: &[u8]) -> bool {
+    if data.len() < ENTRY_SIZE {
+        return false;
+    }
+    let field = |i: usize| LE::read_u32(&data[i * 4..]);
+    let name = &data[36..36 + NAME_LEN];
+    let len = name.iter().position(|c| *c == 0).unwrap_or(NAME_LEN);
+    field(0) <= 1 && field(1) < 16 && len > 0 && name[..len].iter().all(u8::is_ascii_graphic)
+}
+
 impl Pit {
+    /// Reads a PIT of any [`Layout`], the data after the entries is ignored.
     pub fn from_read<R: io::Read>(mut r: R) -> io::Result<Self> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        let mut r = &data[..];
+
         let _signature = r.read_u32::<LE>()?;
         if _signature != PIT_SIGNATURE {
             return Err(io::ErrorKind::InvalidData.into());
         }
         let count = r.read_u32::<LE>()?;
-        let unknown1 = r.read_u32::<LE>()?;
-        let unknown2 = r.read_u32::<LE>()?;
-        let unknown3 = r.read_u16::<LE>()?;
-        let unknown4 = r.read_u16::<LE>()?;
-        let unknown5 = r.read_u16::<LE>()?;
-        let unknown6 = r.read_u16::<LE>()?;
-        let unknown7 = r.read_u16::<LE>()?;
-        let unknown8 = r.read_u16::<LE>()?;
+        let mut gang_name = Name([0; 8]);
+        r.read_exact(&mut gang_name.0)?;
+        let mut project_name = Name([0; 8]);
+        r.read_exact(&mut project_name.0)?;
+        let mut reserved = [0; 4];
+        r.read_exact(&mut reserved)?;
 
+        let layout = Layout::detect(r, count as usize);
         let mut entries = Vec::with_capacity(count as usize);
         for _ in 0..count {
-            entries.push(Entry::from_read(&mut r)?);
+            let mut entry = Entry::from_read(&mut r)?;
+            entry.extra = vec![0; layout.entry_size() - ENTRY_SIZE];
+            r.read_exact(&mut entry.extra)?;
+            entries.push(entry);
         }
         Ok(Self {
             _signature,
-            unknown1,
-            unknown2,
-            unknown3,
-            unknown4,
-            unknown5,
-            unknown6,
-            unknown7,
-            unknown8,
+            gang_name,
+            project_name,
+            reserved,
+            layout,
             entries,
         })
     }
+
+    /// Writes the header and the entries as read by [`from_read`](Self::from_read).
+    pub fn write_to<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_u32::<LE>(self._signature)?;
+        w.write_u32::<LE>(self.entries.len() as u32)?;
+        w.write_all(&self.gang_name.0)?;
+        w.write_all(&self.project_name.0)?;
+        w.write_all(&self.reserved)?;
+        for entry in &self.entries {
+            entry.write_to(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the entry of a partition by its name, ignoring the case.
+    pub fn find_by_name(&self, name: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.partition_name.eq_ignore_ascii_case(name.as_bytes()))
+    }
+
+    /// Finds the entry of a partition by its flash filename, ignoring the case.
+    ///
+    /// If no filename is equal, the `.lz4` and `.ext4` suffixes of both names are
+    /// ignored, e.g. `system.img.ext4.lz4` is found as `system.img`.
+    pub fn find_by_flash_filename(&self, filename: &str) -> Option<&Entry> {
+        let filename = filename.as_bytes();
+        // entries without a flash filename aren't flashed from files
+        let mut entries = self.entries.iter().filter(|e| !e.flash_filename.is_empty());
+        entries
+            .clone()
+            .find(|e| e.flash_filename.eq_ignore_ascii_case(filename))
+            .or_else(|| {
+                let stem = image_stem(filename);
+                entries.find(|e| image_stem(&e.flash_filename).eq_ignore_ascii_case(stem))
+            })
+    }
+
+    /// Finds the entry of a partition by its FOTA filename, ignoring the case.
+    pub fn find_by_fota_filename(&self, filename: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.fota_filename.eq_ignore_ascii_case(filename.as_bytes()))
+    }
+
+    pub fn find_by_identifier(&self, identifier: u32) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.identifier == identifier)
+    }
+
+    /// Returns the entries of the partitions of the given processor.
+    pub fn entries_of_type(&self, kind: BinaryType) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(move |e| e.binary_type == kind)
+    }
+
+    /// Finds the entry of a partition by its name or, as in Heimdall, by its
+    /// identifier.
+    pub fn find_by_name_or_identifier(&self, name: &str) -> Option<&Entry> {
+        self.find_by_name(name).or_else(|| {
+            let identifier = name.parse().ok()?;
+            self.find_by_identifier(identifier)
+        })
+    }
 }
 
-#[derive(Debug)]
+/// Filename without the `.lz4` and `.ext4` suffixes of compressed and sparse images.
+fn image_stem(mut filename: &[u8]) -> &[u8] {
+    for suffix in [&b".lz4"[..], b".ext4"] {
+        if filename.len() > suffix.len() {
+            let (stem, ext) = filename.split_at(filename.len() - suffix.len());
+            if ext.eq_ignore_ascii_case(suffix) {
+                filename = stem;
+            }
+        }
+    }
+    filename
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Entry {
     pub binary_type: BinaryType,
     pub device_type: DeviceType,
@@ -69,9 +198,11 @@ pub struct Entry {
     pub partition_name: Name,
     pub flash_filename: Name,
     pub fota_filename: Name,
+    /// Bytes after the fields of the legacy layout, kept to write the entry back.
+    pub extra: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BinaryType {
     ApplicationProcessor,
     CommunicationProcessor,
@@ -108,13 +239,14 @@ impl fmt::Display for BinaryType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum DeviceType {
     OneNAND,
     File,
     MMC,
     All,
+    UFS,
     Unknown(u32),
 }
 
@@ -125,9 +257,23 @@ impl DeviceType {
             DeviceType::File => 1,
             DeviceType::MMC => 2,
             DeviceType::All => 3,
+            DeviceType::UFS => 8,
             DeviceType::Unknown(val) => *val,
         }
     }
+
+    /// Size of the blocks of the partitions on block devices, the partitions of
+    /// MMC and UFS devices are addressed in 512 and 4096 byte blocks.
+    ///
+    /// OneNAND partitions store their block size in the entry, see
+    /// [`Entry::block_size`].
+    pub fn block_size(&self) -> Option<u32> {
+        match self {
+            DeviceType::MMC => Some(512),
+            DeviceType::UFS => Some(4096),
+            _ => None,
+        }
+    }
 }
 
 impl From<u32> for DeviceType {
@@ -137,6 +283,7 @@ impl From<u32> for DeviceType {
             1 => DeviceType::File,
             2 => DeviceType::MMC,
             3 => DeviceType::All,
+            8 => DeviceType::UFS,
             n => DeviceType::Unknown(n),
         }
     }
@@ -149,6 +296,7 @@ impl fmt::Display for DeviceType {
             DeviceType::File => f.write_str("File/FAT"),
             DeviceType::MMC => f.write_str("MMC"),
             DeviceType::All => f.write_str("All (?)"),
+            DeviceType::UFS => f.write_str("UFS"),
             DeviceType::Unknown(val) => f.write_fmt(format_args!("UNKNOWN({})", val)),
         }
     }
@@ -169,6 +317,7 @@ bitflags::bitflags! {
 }
 
 impl Entry {
+    /// Reads the fields of the legacy layout.
     pub fn from_read<R: io::Read>(mut r: R) -> io::Result<Entry> {
         let binary_type = r.read_u32::<LE>()?;
         let device_type = r.read_u32::<LE>()?;
@@ -179,11 +328,11 @@ impl Entry {
         let block_count = r.read_u32::<LE>()?;
         let file_offset = r.read_u32::<LE>()?;
         let file_size = r.read_u32::<LE>()?;
-        let mut partition_name = Name([0; 32]);
+        let mut partition_name = Name([0; NAME_LEN]);
         r.read_exact(&mut partition_name.0)?;
-        let mut flash_filename = Name([0; 32]);
+        let mut flash_filename = Name([0; NAME_LEN]);
         r.read_exact(&mut flash_filename.0)?;
-        let mut fota_filename = Name([0; 32]);
+        let mut fota_filename = Name([0; NAME_LEN]);
         r.read_exact(&mut fota_filename.0)?;
         Ok(Entry {
             binary_type: BinaryType::from(binary_type),
@@ -198,34 +347,358 @@ impl Entry {
             partition_name,
             flash_filename,
             fota_filename,
+            extra: vec![],
         })
     }
+
+    /// Size of the blocks of the partition in bytes.
+    ///
+    /// OneNAND PITs store the block size in `blocksize_or_offset`, newer PITs of
+    /// MMC and UFS devices store the first block of the partition instead.
+    pub fn block_size(&self) -> Option<u32> {
+        match self.device_type {
+            DeviceType::OneNAND => Some(self.blocksize_or_offset),
+            device_type => device_type.block_size(),
+        }
+    }
+
+    /// First block of the partition, if it's stored in the entry.
+    pub fn start_block(&self) -> Option<u32> {
+        self.device_type
+            .block_size()
+            .map(|_| self.blocksize_or_offset)
+    }
+
+    /// Offset of the partition on the device in bytes.
+    pub fn offset(&self) -> Option<u64> {
+        let block_size = self.device_type.block_size()?;
+        Some(u64::from(self.start_block()?) * u64::from(block_size))
+    }
+
+    /// Size of the partition in bytes.
+    pub fn size(&self) -> Option<u64> {
+        Some(u64::from(self.block_count) * u64::from(self.block_size()?))
+    }
+
+    pub fn write_to<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_u32::<LE>(self.binary_type.as_u32())?;
+        w.write_u32::<LE>(self.device_type.as_u32())?;
+        w.write_u32::<LE>(self.identifier)?;
+        w.write_u32::<LE>(self.attributes.bits())?;
+        w.write_u32::<LE>(self.update_attributes.bits())?;
+        w.write_u32::<LE>(self.blocksize_or_offset)?;
+        w.write_u32::<LE>(self.block_count)?;
+        w.write_u32::<LE>(self.file_offset)?;
+        w.write_u32::<LE>(self.file_size)?;
+        w.write_all(&self.partition_name.0)?;
+        w.write_all(&self.flash_filename.0)?;
+        w.write_all(&self.fota_filename.0)?;
+        w.write_all(&self.extra)
+    }
 }
 
-pub struct Name([u8; 32]);
+const NAME_LEN: usize = 32;
+
+/// NUL-padded string of a fixed size.
+pub struct Name<const N: usize = NAME_LEN>([u8; N]);
+
+impl<const N: usize> Name<N> {
+    /// Returns all bytes including the padding.
+    pub fn raw(&self) -> &[u8; N] {
+        &self.0
+    }
+}
 
-impl Deref for Name {
+impl<const N: usize> Deref for Name<N> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        let pos = self.0.iter().position(|c| *c == 0).unwrap_or(32);
+        let pos = self.0.iter().position(|c| *c == 0).unwrap_or(N);
         &self.0[0..pos]
     }
 }
 
-impl fmt::Debug for Name {
+/// Names are equal if the strings are, the bytes after the NUL are ignored.
+impl<const N: usize> PartialEq for Name<N> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<const N: usize> Eq for Name<N> {}
+
+impl<const N: usize> fmt::Debug for Name<N> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!("{:X?}", self.0))
     }
 }
 
-impl fmt::Display for Name {
+impl<const N: usize> fmt::Display for Name<N> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let pos = self.0.iter().position(|c| *c == 0).unwrap_or(32);
-        if let Ok(s) = std::str::from_utf8(&self.0[0..pos]) {
+        if let Ok(s) = std::str::from_utf8(self) {
             f.write_str(s)
         } else {
             f.write_str("invalid uft8 data")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name<const N: usize>(s: &str) -> Name<N> {
+        let mut name = Name([0; N]);
+        name.0[..s.len()].copy_from_slice(s.as_bytes());
+        name
+    }
+
+    fn entry(identifier: u32, partition_name: &str, flash_filename: &str) -> Entry {
+        Entry {
+            binary_type: BinaryType::ApplicationProcessor,
+            device_type: DeviceType::MMC,
+            identifier,
+            attributes: Attributes::WRITE,
+            update_attributes: UpdateAttributes::empty(),
+            blocksize_or_offset: 0,
+            block_count: 0,
+            file_offset: 0,
+            file_size: 0,
+            partition_name: name(partition_name),
+            flash_filename: name(flash_filename),
+            fota_filename: name(""),
+            extra: vec![],
+        }
+    }
+
+    fn pit(entries: Vec<Entry>) -> Pit {
+        Pit {
+            _signature: PIT_SIGNATURE,
+            gang_name: name("COM_TAR2"),
+            project_name: name("MSM8996"),
+            reserved: [0; 4],
+            layout: Layout::Legacy,
+            entries,
+        }
+    }
+
+    fn padded(s: &[u8], len: usize) -> Vec<u8> {
+        let mut buf = s.to_vec();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// PIT of two entries, the names have stale bytes after the NUL.
+    fn fixture() -> Vec<u8> {
+        fixture_with(&[])
+    }
+
+    /// PIT of a newer layout, its entries end with `extra`.
+    fn fixture_with(extra: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&PIT_SIGNATURE.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(b"COM_TAR2");
+        buf.extend_from_slice(b"MSM8996\0");
+        buf.extend_from_slice(&[0x01, 0x00, 0xAB, 0xCD]);
+        for (id, name, flash_filename) in [
+            (70u32, &b"BOOTLOADER"[..], &b"sboot.bin\0old"[..]),
+            (80, b"SYSTEM\0X", b"system.img.ext4"),
+        ] {
+            let fields = [0, 2, id, 1, 0x10, 0x2000, 0x8000, 0, 0];
+            for field in fields {
+                buf.extend_from_slice(&field.to_le_bytes());
+            }
+            buf.extend(padded(name, NAME_LEN));
+            buf.extend(padded(flash_filename, NAME_LEN));
+            buf.extend(padded(b"-", NAME_LEN));
+            buf.extend_from_slice(extra);
+        }
+        buf
+    }
+
+    #[test]
+    fn read_fixture() {
+        let pit = Pit::from_read(&fixture()[..]).unwrap();
+        assert_eq!(pit.gang_name.to_string(), "COM_TAR2");
+        assert_eq!(pit.project_name.to_string(), "MSM8996");
+        assert_eq!(pit.reserved, [0x01, 0x00, 0xAB, 0xCD]);
+        assert_eq!(pit.entries.len(), 2);
+
+        let entry = &pit.entries[1];
+        assert_eq!(entry.binary_type, BinaryType::ApplicationProcessor);
+        assert_eq!(entry.device_type, DeviceType::MMC);
+        assert_eq!(entry.identifier, 80);
+        assert_eq!(entry.attributes, Attributes::WRITE);
+        assert_eq!(entry.update_attributes, UpdateAttributes::SECURE);
+        assert_eq!(entry.partition_name.to_string(), "SYSTEM");
+        assert_eq!(&entry.partition_name.raw()[..9], b"SYSTEM\0X\0");
+        assert_eq!(entry.flash_filename.to_string(), "system.img.ext4");
+    }
+
+    #[test]
+    fn write_read_pit() {
+        let data = fixture();
+        let pit = Pit::from_read(&data[..]).unwrap();
+        let mut written = vec![];
+        pit.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn detect_legacy_layout() {
+        let mut data = fixture();
+        // dumped PITs are padded
+        data.resize(4096, 0);
+        let pit = Pit::from_read(&data[..]).unwrap();
+        assert_eq!(pit.layout, Layout::Legacy);
+        assert_eq!(pit.entries[1].partition_name.to_string(), "SYSTEM");
+        assert!(pit.entries[1].extra.is_empty());
+    }
+
+    #[test]
+    fn detect_legacy_layout_resembling_extended() {
+        let mut entries = vec![
+            entry(1, "BOOTLOADER", "sboot.bin"),
+            entry(2, "HIDDEN_PARTITION_NAME", "hidden.img"),
+            entry(3, "SYSTEM", "system.img"),
+        ];
+        entries[1].update_attributes = UpdateAttributes::FOTA;
+        let mut data = vec![];
+        pit(entries).write_to(&mut data).unwrap();
+        data.resize(4096, 0);
+
+        // the update attributes, block size and the end of the name of the second
+        // entry also look like an entry 148 bytes after the first one
+        let entries = &data[28..];
+        assert!(is_entry(&entries[148..]));
+        let pit = Pit::from_read(&data[..]).unwrap();
+        assert_eq!(pit.layout, Layout::Legacy);
+        let names = pit.entries.iter().map(|e| e.partition_name.to_string());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            ["BOOTLOADER", "HIDDEN_PARTITION_NAME", "SYSTEM"]
+        );
+    }
+
+    #[test]
+    fn detect_extended_layout() {
+        let extra = [0x5A; 16];
+        let data = fixture_with(&extra);
+        let pit = Pit::from_read(&data[..]).unwrap();
+        assert_eq!(pit.layout, Layout::Extended { entry_size: 148 });
+        assert_eq!(pit.entries.len(), 2);
+        for (entry, id) in pit.entries.iter().zip([70, 80]) {
+            assert_eq!(entry.identifier, id);
+            assert_eq!(entry.extra, extra);
+        }
+        assert_eq!(pit.entries[1].partition_name.to_string(), "SYSTEM");
+        assert_eq!(pit.entries[1].flash_filename.to_string(), "system.img.ext4");
+
+        let mut written = vec![];
+        pit.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+
+        let mut padded = data.clone();
+        padded.resize(8192, 0);
+        let pit = Pit::from_read(&padded[..]).unwrap();
+        assert_eq!(pit.layout, Layout::Extended { entry_size: 148 });
+    }
+
+    #[test]
+    fn partition_location() {
+        let mut entry = entry(1, "SYSTEM", "system.img");
+        entry.blocksize_or_offset = 0x2000;
+        entry.block_count = 0x8000;
+        assert_eq!(entry.block_size(), Some(512));
+        assert_eq!(entry.start_block(), Some(0x2000));
+        assert_eq!(entry.offset(), Some(0x2000 * 512));
+        assert_eq!(entry.size(), Some(0x8000 * 512));
+
+        entry.device_type = DeviceType::from(8);
+        assert_eq!(entry.device_type, DeviceType::UFS);
+        assert_eq!(entry.block_size(), Some(4096));
+        assert_eq!(entry.offset(), Some(0x2000 * 4096));
+        assert_eq!(entry.size(), Some(0x8000 * 4096));
+
+        // OneNAND entries store the block size instead of the first block
+        entry.device_type = DeviceType::OneNAND;
+        entry.blocksize_or_offset = 0x40000;
+        entry.block_count = 4;
+        assert_eq!(entry.block_size(), Some(0x40000));
+        assert_eq!(entry.start_block(), None);
+        assert_eq!(entry.offset(), None);
+        assert_eq!(entry.size(), Some(4 * 0x40000));
+
+        entry.device_type = DeviceType::File;
+        assert_eq!(entry.block_size(), None);
+        assert_eq!(entry.size(), None);
+    }
+
+    #[test]
+    fn reject_invalid_signature() {
+        let mut data = fixture();
+        data[0] ^= 0xFF;
+        let e = Pit::from_read(&data[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let e = Pit::from_read(&fixture()[..100]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn strip_image_suffixes() {
+        assert_eq!(image_stem(b"system.img.ext4.lz4"), b"system.img");
+        assert_eq!(image_stem(b"system.img.ext4"), b"system.img");
+        assert_eq!(image_stem(b"boot.img.lz4"), b"boot.img");
+        assert_eq!(image_stem(b"BOOT.IMG.LZ4"), b"BOOT.IMG");
+        assert_eq!(image_stem(b"boot.img"), b"boot.img");
+        // only in this order and never the whole name
+        assert_eq!(image_stem(b"system.lz4.ext4"), b"system.lz4");
+        assert_eq!(image_stem(b".lz4"), b".lz4");
+        assert_eq!(image_stem(b""), b"");
+    }
+
+    #[test]
+    fn entries_of_type() {
+        let mut data = fixture();
+        // the second entry is a partition of the modem
+        data[28 + ENTRY_SIZE] = 1;
+        let pit = Pit::from_read(&data[..]).unwrap();
+        let ids = |kind| {
+            pit.entries_of_type(kind)
+                .map(|e| e.identifier)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(BinaryType::ApplicationProcessor), [70]);
+        assert_eq!(ids(BinaryType::CommunicationProcessor), [80]);
+        assert_eq!(ids(BinaryType::Unknown(2)), []);
+    }
+
+    #[test]
+    fn find_by_flash_filename() {
+        let pit = pit(vec![
+            entry(1, "EFS", ""),
+            entry(2, "BOOT", "boot.img"),
+            entry(3, "SYSTEM", "system.img.ext4"),
+            entry(4, "CACHE", "cache.img"),
+            entry(5, "CACHE_LZ4", "cache.img.lz4"),
+        ]);
+        let find = |filename| pit.find_by_flash_filename(filename).map(|e| e.identifier);
+
+        assert_eq!(find("boot.img"), Some(2));
+        assert_eq!(find("BOOT.IMG"), Some(2));
+        assert_eq!(find("boot.img.lz4"), Some(2));
+        assert_eq!(find("system.img.ext4"), Some(3));
+        assert_eq!(find("system.img"), Some(3));
+        assert_eq!(find("System.img.ext4.LZ4"), Some(3));
+        // an equal filename wins over an equal stem
+        assert_eq!(find("cache.img.lz4"), Some(5));
+        assert_eq!(find("cache.img"), Some(4));
+        // entries without a filename never match
+        assert_eq!(find(""), None);
+        assert_eq!(find(".lz4"), None);
+        assert_eq!(find("recovery.img"), None);
+    }
+}
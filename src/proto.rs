@@ -1,361 +1,392 @@
-use std::convert::TryInto;
-use std::fmt;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use tracing::instrument;
 
+pub mod decode;
 mod error;
+pub mod machine;
+pub mod packet;
+pub mod typestate;
 mod util;
 
-use crate::device::Handle;
-use error::Error;
-use util::BatchIterator;
-use util::HandleExt;
+pub use error::Error;
+pub use machine::{Action, Event, Machine, Phase};
+pub use packet::FileTarget;
+pub(crate) use util::read_ahead;
 
-macro_rules! consts {
-    ($($(#[$outer:meta])* const $name:ident = $value:expr;)+) => {
-        $($(#[$outer])* const $name: [u8; 4] = u32::to_le_bytes($value);)*
-    }
-}
+/// Bulk transfer endpoints the protocol functions talk to.
+///
+/// Implemented by [`Handle`](crate::device::Handle) for real devices and by
+/// [`Replay`](crate::record::Replay) for recorded sessions.
+///
+/// The transport also keeps the [`Phase`] the protocol functions reached, so they
+/// reject the operations the device doesn't accept next like a [`Machine`] does.
+pub trait Transport {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error>;
 
-consts! {
-    const CONTROL_TYPE_SESSION = 0x64;
-    const CONTROL_TYPE_PIT_FILE = 0x65;
-    #[allow(dead_code)]
-    const CONTROL_TYPE_FILE_TRANSFER = 0x66;
-    const CONTROL_TYPE_END_SESSION= 0x67;
-
-    const SESSION_REQUEST_TYPE_BEGIN_SESSION = 0x00;
-    #[allow(dead_code)]
-    const SESSION_REQUEST_TYPE_DEVICE_TYPE = 0x01;
-    const SESSION_REQUEST_TYPE_TOTAL_BYTES = 0x02;
-    #[allow(dead_code)]
-    const SESSION_REQUEST_TYPE_FILE_PART_SIZE = 0x05;
-    #[allow(dead_code)]
-    const SESSION_REQUEST_TYPE_ENABLE_TFLASH = 0x08;
-
-    const END_SESSION_REQUEST_TYPE_END_SESSION = 0x00;
-    const END_SESSION_REQUEST_TYPE_REBOOT = 0x01;
-
-    const FILE_REQUEST_TYPE_FLASH = 0x00;
-    const FILE_REQUEST_TYPE_PART = 0x02;
-    const FILE_REQUEST_TYPE_END_TRANSFER = 0x03;
-
-    const FILE_END_TRANSFER_DEST_PHONE = 0x00;
-    const FILE_END_TRANSFER_DEST_MODEM = 0x01;
-
-    const PIT_REQUEST_TYPE_DUMP = 0x01;
-    const PIT_REQUEST_TYPE_PART = 0x02;
-    const PIT_REQUEST_TYPE_END_TRANSFER = 0x03;
-
-    const RESPONSE_TYPE_SEND_FILE_PART = 0x00;
-    const RESPONSE_TYPE_SETUP_SESSION = 0x64;
-    const RESPONSE_TYPE_PIT_FILE = 0x65;
-    const RESPONSE_TYPE_FILE_TRANSFER = 0x66;
-    const RESPONSE_TYPE_END_SESSION = 0x67;
-}
+    fn write(&self, buf: &[u8]) -> Result<usize, Error>;
 
-#[instrument(skip(handle))]
-pub fn handshake(handle: &Handle) -> Result<(), Error> {
-    handle.write(b"ODIN")?;
+    fn phase(&self) -> Phase;
 
-    let mut buf = [0; 8];
-    match handle.read(&mut buf)? {
-        n if n == 4 && &buf[..4] == b"LOKE" => Ok(()),
-        _ => Err(Error::Handshake),
-    }
+    fn set_phase(&self, phase: Phase);
 }
 
-#[instrument(skip(handle))]
-pub fn begin_session(handle: &Handle) -> Result<u32, Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_SESSION);
-    buf[4..8].copy_from_slice(&SESSION_REQUEST_TYPE_BEGIN_SESSION);
-    // Odin version
-    buf[8..12].copy_from_slice(&4u32.to_le_bytes());
-
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
-
-    assert_eq!(buf[0..4], RESPONSE_TYPE_SETUP_SESSION);
+/// Size of the chunks the files are sent in.
+pub(crate) const FILE_PART_SIZE: u32 = 1024 * 1024;
+
+/// Odin version announced when beginning a session.
+const ODIN_VERSION: u32 = 4;
+
+/// Runs the started operation of the machine to its end with blocking transfers.
+///
+/// `file` provides the data of a file transfer.
+pub fn drive(
+    handle: &impl Transport,
+    machine: &mut Machine,
+    file: Option<&mut dyn Read>,
+) -> Result<Event, Error> {
+    drive_cancellable(handle, machine, file, || false)
+}
 
-    let default_packet_size = u32::from_le_bytes((&buf[4..8]).try_into().unwrap());
-    tracing::debug!(default_packet_size, "in:  {:X?}", buf);
-    Ok(default_packet_size)
+/// Like [`drive`], but checks `is_cancelled` before every transfer and aborts the
+/// operation with [`Error::Cancelled`] once it returns `true`.
+pub fn drive_cancellable<C>(
+    handle: &impl Transport,
+    machine: &mut Machine,
+    file: Option<&mut dyn Read>,
+    is_cancelled: C,
+) -> Result<Event, Error>
+where
+    C: Fn() -> bool,
+{
+    let before = |machine: &mut Machine| {
+        if is_cancelled() {
+            machine.abort();
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    };
+    drive_with(handle, machine, file, before, |_| {})
 }
 
-#[instrument(skip(handle))]
-pub fn setup_file_part_size(handle: &Handle, size: u32) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_SESSION);
-    buf[4..8].copy_from_slice(&SESSION_REQUEST_TYPE_FILE_PART_SIZE);
-    buf[8..12].copy_from_slice(&size.to_le_bytes());
+/// Like [`drive`], but stops a running file transfer after the current batch once
+/// `stop` is set, see [`Machine::stop_transfer`].
+pub fn drive_until(
+    handle: &impl Transport,
+    machine: &mut Machine,
+    file: Option<&mut dyn Read>,
+    stop: &AtomicBool,
+) -> Result<Event, Error> {
+    drive_until_sent(handle, machine, file, stop, |_| {})
+}
 
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
+/// Like [`drive_until`], but passes the file data of every chunk to `sent` once its
+/// bulk write completed, see [`Machine::sent_chunk`].
+pub fn drive_until_sent<S>(
+    handle: &impl Transport,
+    machine: &mut Machine,
+    file: Option<&mut dyn Read>,
+    stop: &AtomicBool,
+    sent: S,
+) -> Result<Event, Error>
+where
+    S: FnMut(&[u8]),
+{
+    let before = |machine: &mut Machine| {
+        if stop.load(Ordering::Relaxed) {
+            machine.stop_transfer();
+        }
+        Ok(())
+    };
+    drive_with(handle, machine, file, before, sent)
+}
 
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+/// Drives the machine, calling `before` ahead of every transfer and `sent` after
+/// every written chunk.
+fn drive_with<F, S>(
+    handle: &impl Transport,
+    machine: &mut Machine,
+    mut file: Option<&mut dyn Read>,
+    mut before: F,
+    mut sent: S,
+) -> Result<Event, Error>
+where
+    F: FnMut(&mut Machine) -> Result<(), Error>,
+    S: FnMut(&[u8]),
+{
+    loop {
+        if machine.is_busy() {
+            before(machine)?;
+        }
+        let n = match machine.poll() {
+            Action::Write(buf) => handle.write(buf),
+            Action::Read(buf) => handle.read(buf),
+            Action::Fill(buf) => match file.as_mut() {
+                Some(file) => util::fill_buf(file, buf).map_err(Error::from),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no file").into()),
+            },
+            Action::Done(event) => return Ok(event),
+            Action::Idle => {
+                return Err(Error::Sequence {
+                    operation: "drive",
+                    state: "idle".into(),
+                })
+            }
+        };
+        let n = n.inspect_err(|_| machine.abort())?;
+        machine.complete(n)?;
+        if let Some(data) = machine.sent_chunk() {
+            sent(data);
+        }
+    }
+}
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_SETUP_SESSION);
+/// Runs a single operation on a machine resumed in the phase of the handle.
+pub(crate) fn run<F>(handle: &impl Transport, start: F) -> Result<Event, Error>
+where
+    F: FnOnce(&mut Machine) -> Result<(), Error>,
+{
+    run_with(handle, start, |machine| drive(handle, machine, None))
+}
 
-    let result = u32::from_le_bytes((&buf[4..8]).try_into().unwrap());
-    tracing::debug!(result, "in:  {:X?}", buf);
-    Ok(())
+/// Like [`run`], but lets `drive` run the started operation.
+pub(crate) fn run_with<T, F, D>(handle: &T, start: F, drive: D) -> Result<Event, Error>
+where
+    T: Transport,
+    F: FnOnce(&mut Machine) -> Result<(), Error>,
+    D: FnOnce(&mut Machine) -> Result<Event, Error>,
+{
+    let mut machine = Machine::resume(handle.phase());
+    start(&mut machine)?;
+    let result = drive(&mut machine);
+    handle.set_phase(machine.phase());
+    result
 }
 
 #[instrument(skip(handle))]
-pub fn send_total_size(handle: &Handle, size: u64) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_SESSION);
-    buf[4..8].copy_from_slice(&SESSION_REQUEST_TYPE_TOTAL_BYTES);
-    buf[8..16].copy_from_slice(&size.to_le_bytes());
-
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
-
-    assert_eq!(buf[0..4], RESPONSE_TYPE_SETUP_SESSION);
+pub fn handshake(handle: &impl Transport) -> Result<(), Error> {
+    run(handle, Machine::handshake)?;
     Ok(())
 }
 
 #[instrument(skip(handle))]
-fn begin_file_transfer(handle: &Handle) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_FILE_TRANSFER);
-    buf[4..8].copy_from_slice(&FILE_REQUEST_TYPE_FLASH);
-
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+pub fn begin_session(handle: &impl Transport) -> Result<u32, Error> {
+    match run(handle, Machine::begin_session)? {
+        Event::SessionBegun {
+            default_packet_size,
+        } => Ok(default_packet_size),
+        _ => unreachable!(),
+    }
+}
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_FILE_TRANSFER);
+#[instrument(skip(handle))]
+pub fn setup_file_part_size(handle: &impl Transport, size: u32) -> Result<(), Error> {
+    run(handle, |m| m.setup_file_part_size(size))?;
     Ok(())
 }
 
 #[instrument(skip(handle))]
-fn begin_batch_file_transfer(handle: &Handle, size: u32) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_FILE_TRANSFER);
-    buf[4..8].copy_from_slice(&FILE_REQUEST_TYPE_PART);
-    buf[8..12].copy_from_slice(&size.to_le_bytes());
-
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
-
-    assert_eq!(buf[0..4], RESPONSE_TYPE_FILE_TRANSFER);
+pub fn send_total_size(handle: &impl Transport, size: u64) -> Result<(), Error> {
+    run(handle, |m| m.send_total_size(size))?;
     Ok(())
 }
 
-#[instrument(skip(handle, chunk))]
-fn send_file_chunk(handle: &Handle, chunk_idx: u32, chunk: &[u8]) -> Result<(), Error> {
-    tracing::debug!("out: {:X?}", &chunk[..16]);
-    handle.write(chunk)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
-
-    assert_eq!(buf[0..4], RESPONSE_TYPE_SEND_FILE_PART);
-
-    let resp_n = u32::from_le_bytes((&buf[4..8]).try_into().unwrap());
-    assert_eq!(chunk_idx, resp_n);
-    tracing::debug!(resp_n, "in: {:X?}", &buf);
+#[instrument(skip(handle, file))]
+pub fn file_transfer<R: Read>(
+    handle: &impl Transport,
+    target: &FileTarget,
+    file: &mut R,
+    file_size: u64,
+) -> Result<(), Error> {
+    run_with(
+        handle,
+        |m| m.file_transfer(target, file_size),
+        |m| drive(handle, m, Some(file)),
+    )?;
     Ok(())
 }
 
-#[instrument(skip(handle))]
-fn end_batch_file_transfer(
-    handle: &Handle,
+/// Like [`file_transfer`], but stops after the current batch once `stop` is set.
+///
+/// Returns whether the whole file was transferred.
+#[instrument(skip(handle, file, stop))]
+pub fn file_transfer_until<R: Read>(
+    handle: &impl Transport,
     target: &FileTarget,
-    effective_size: u32,
-    eof: bool,
-) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_FILE_TRANSFER);
-    buf[4..8].copy_from_slice(&FILE_REQUEST_TYPE_END_TRANSFER);
-
-    buf[12..16].copy_from_slice(&effective_size.to_le_bytes());
-    buf[16..20].copy_from_slice(&u32::to_le_bytes(0)); // unknown1
-    match target {
-        FileTarget::ApplicationProcessor {
-            device_type,
-            identifier,
-        } => {
-            buf[8..12].copy_from_slice(&FILE_END_TRANSFER_DEST_PHONE);
-
-            buf[20..24].copy_from_slice(&device_type.to_le_bytes());
-            buf[24..28].copy_from_slice(&identifier.to_le_bytes());
-            buf[28..32].copy_from_slice(&u32::from(eof).to_le_bytes());
-        }
-        FileTarget::CommunicationProcessor { device_type } => {
-            buf[8..12].copy_from_slice(&FILE_END_TRANSFER_DEST_MODEM);
+    file: &mut R,
+    file_size: u64,
+    stop: &AtomicBool,
+) -> Result<bool, Error> {
+    let event = run_with(
+        handle,
+        |m| m.file_transfer(target, file_size),
+        |m| drive_until(handle, m, Some(file), stop),
+    )?;
+    Ok(matches!(event, Event::FileTransferred))
+}
 
-            buf[20..24].copy_from_slice(&device_type.to_le_bytes());
-            buf[24..28].copy_from_slice(&u32::from(eof).to_le_bytes());
-        }
+#[instrument(skip(handle))]
+pub fn receive_pit(handle: &impl Transport) -> Result<Vec<u8>, Error> {
+    match run(handle, Machine::receive_pit)? {
+        Event::PitReceived(pit) => Ok(pit),
+        _ => unreachable!(),
     }
-    tracing::debug!("out: {:X?}", &buf[..32]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+}
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_FILE_TRANSFER);
+#[instrument(skip(handle, pit))]
+pub fn flash_pit(handle: &impl Transport, pit: &[u8]) -> Result<(), Error> {
+    run(handle, |m| m.flash_pit(pit.to_vec()))?;
     Ok(())
 }
 
-pub enum FileTarget {
-    ApplicationProcessor { device_type: u32, identifier: u32 },
-    CommunicationProcessor { device_type: u32 },
+#[instrument(skip(handle))]
+pub fn end_session(handle: &impl Transport) -> Result<(), Error> {
+    run(handle, Machine::end_session)?;
+    Ok(())
 }
 
-impl fmt::Debug for FileTarget {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            FileTarget::ApplicationProcessor {
-                device_type,
-                identifier,
-            } => f
-                .debug_struct("AP")
-                .field("device_type", device_type)
-                .field("identifier", identifier)
-                .finish(),
-            FileTarget::CommunicationProcessor { device_type } => f
-                .debug_struct("CP")
-                .field("device_type", device_type)
-                .finish(),
-        }
-    }
+#[instrument(skip(handle))]
+pub fn reboot(handle: &impl Transport) -> Result<(), Error> {
+    run(handle, Machine::reboot)?;
+    Ok(())
 }
 
-use std::io::Read;
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
 
-#[instrument(skip(handle, file))]
-pub fn file_transfer<R: Read>(
-    handle: &Handle,
-    target: &FileTarget,
-    file: &mut R,
-    file_size: u64,
-) -> Result<(), Error> {
-    begin_file_transfer(handle)?;
+    use super::packet::{Response, ResponseType};
+    use super::*;
 
-    let it = BatchIterator::new(file_size, 1024 * 1024, 30);
-
-    for batch in it {
-        begin_batch_file_transfer(handle, batch.size())?;
-        for n in batch.chunks() {
-            let mut buf = vec![0; 1024 * 1024];
-            util::fill_buf(file, &mut buf)?;
+    /// Device answering the reads with scripted data and counting the writes.
+    struct Scripted {
+        reads: RefCell<VecDeque<Vec<u8>>>,
+        writes: Cell<usize>,
+        phase: Cell<Phase>,
+    }
 
-            handle.with_post_write_op(|handle| send_file_chunk(handle, n, &buf))?;
+    impl Scripted {
+        fn new(reads: Vec<Vec<u8>>) -> Self {
+            Self {
+                reads: RefCell::new(reads.into()),
+                writes: Cell::new(0),
+                phase: Cell::new(Phase::Connected),
+            }
         }
-        end_batch_file_transfer(handle, target, batch.effective_size(), batch.is_last())?;
     }
-    Ok(())
-}
 
-#[instrument(skip(handle))]
-pub fn receive_pit(handle: &Handle) -> Result<Vec<u8>, Error> {
-    tracing::debug!("start pit transfer");
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_PIT_FILE);
-    buf[4..8].copy_from_slice(&PIT_REQUEST_TYPE_DUMP);
-
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
-
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
-
-    assert_eq!(buf[0..4], RESPONSE_TYPE_PIT_FILE);
-    let pit_size = u32::from_le_bytes((&buf[4..8]).try_into().unwrap());
-    tracing::debug!(pit_size, "in:  {:X?}", buf);
-    let mut pit_buf = vec![0; pit_size as usize];
-
-    let mut req_buf = vec![0; 1024];
-    req_buf[0..4].copy_from_slice(&CONTROL_TYPE_PIT_FILE);
-    req_buf[4..8].copy_from_slice(&PIT_REQUEST_TYPE_PART);
-
-    handle.with_post_read_op(|handle| {
-        let it = pit_buf.chunks_mut(500).enumerate();
-        let (count, _) = it.size_hint();
-        tracing::debug!(count);
-        for (i, res_buf) in it {
-            req_buf[8..12].copy_from_slice(&(i as u32).to_le_bytes());
-
-            tracing::debug!("out: {:X?}", &req_buf[..16]);
-            handle.write(&req_buf)?;
-
-            let n = handle.read(res_buf)?;
-            tracing::debug!(n, "in:  {:X?}", &res_buf[..16]);
+    impl Transport for Scripted {
+        fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+            let data = self.reads.borrow_mut().pop_front().expect("scripted read");
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
         }
-        Ok(())
-    })?;
-
-    tracing::debug!("end pit transfer");
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_PIT_FILE);
-    buf[4..8].copy_from_slice(&PIT_REQUEST_TYPE_END_TRANSFER);
 
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
+        fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+            self.writes.set(self.writes.get() + 1);
+            Ok(buf.len())
+        }
 
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+        fn phase(&self) -> Phase {
+            self.phase.get()
+        }
 
-    tracing::debug!("in:  {:X?}", buf);
+        fn set_phase(&self, phase: Phase) {
+            self.phase.set(phase);
+        }
+    }
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_PIT_FILE);
-    Ok(pit_buf)
-}
+    fn response(kind: ResponseType, value: u32) -> Vec<u8> {
+        Response { kind, value }.encode().to_vec()
+    }
 
-#[instrument(skip(handle))]
-pub fn end_session(handle: &Handle) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_END_SESSION);
-    buf[4..8].copy_from_slice(&END_SESSION_REQUEST_TYPE_END_SESSION);
+    fn session(reads: Vec<Vec<u8>>) -> Scripted {
+        let mut script = vec![b"LOKE".to_vec(), response(ResponseType::Session, 0)];
+        script.extend(reads);
+        let device = Scripted::new(script);
+        handshake(&device).unwrap();
+        begin_session(&device).unwrap();
+        device
+    }
 
-    tracing::debug!("out: {:X?}", &buf[..16]);
-    handle.write(&buf)?;
+    fn assert_rejected<T: std::fmt::Debug>(device: &Scripted, result: Result<T, Error>) {
+        let writes = device.writes.get();
+        assert!(
+            matches!(result, Err(Error::Sequence { .. })),
+            "expected sequence error, got {:?}",
+            result
+        );
+        assert_eq!(device.writes.get(), writes, "request reached the device");
+    }
 
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+    #[test]
+    fn reject_file_transfer_before_total_size() {
+        let target = FileTarget::CommunicationProcessor { device_type: 1 };
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_END_SESSION);
-    Ok(())
-}
+        let device = Scripted::new(vec![]);
+        let result = file_transfer(&device, &target, &mut &[0u8; 10][..], 10);
+        assert_rejected(&device, result);
 
-#[instrument(skip(handle))]
-pub fn reboot(handle: &Handle) -> Result<(), Error> {
-    let mut buf = vec![0; 1024];
-    buf[0..4].copy_from_slice(&CONTROL_TYPE_END_SESSION);
-    buf[4..8].copy_from_slice(&END_SESSION_REQUEST_TYPE_REBOOT);
+        let device = session(vec![]);
+        let result = file_transfer(&device, &target, &mut &[0u8; 10][..], 10);
+        assert_rejected(&device, result);
+        assert_eq!(device.phase(), Phase::Session);
+    }
 
-    handle.write(&buf)?;
+    #[test]
+    fn reject_receiving_pit_twice() {
+        let device = session(vec![
+            response(ResponseType::PitFile, 4),
+            vec![1, 2, 3, 4],
+            vec![],
+            response(ResponseType::PitFile, 0),
+        ]);
+        assert_eq!(receive_pit(&device).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(device.phase(), Phase::PitReceived);
+
+        let result = receive_pit(&device);
+        assert_rejected(&device, result);
+    }
 
-    let mut buf = vec![0; 8];
-    let n = handle.read(&mut buf)?;
-    debug_assert_eq!(n, 8);
+    #[test]
+    fn share_phase_with_typestate() {
+        let device = Scripted::new(vec![
+            b"LOKE".to_vec(),
+            response(ResponseType::Session, 0),
+            response(ResponseType::PitFile, 4),
+            vec![1, 2, 3, 4],
+            vec![],
+            response(ResponseType::PitFile, 0),
+            response(ResponseType::Session, 0),
+        ]);
+        let (session, _) = typestate::Connected::new(&device)
+            .handshake()
+            .unwrap()
+            .begin_session()
+            .unwrap();
+        assert_eq!(device.phase(), Phase::Session);
+
+        let (_session, pit) = session.receive_pit().unwrap();
+        assert_eq!(pit, [1, 2, 3, 4]);
+        let result = receive_pit(&device);
+        assert_rejected(&device, result);
+
+        send_total_size(&device, 10).unwrap();
+        assert_eq!(device.phase(), Phase::TotalSizeSent);
+    }
 
-    assert_eq!(buf[0..4], RESPONSE_TYPE_END_SESSION);
-    Ok(())
+    #[test]
+    fn reject_after_failure() {
+        let device = session(vec![response(ResponseType::PitFile, 0)]);
+        assert!(matches!(
+            send_total_size(&device, 10),
+            Err(Error::UnexpectedResponse { .. })
+        ));
+        assert_eq!(device.phase(), Phase::Failed);
+
+        let result = end_session(&device);
+        assert_rejected(&device, result);
+    }
 }
@@ -0,0 +1,221 @@
+//! Decoding of captured packets into named Odin messages.
+use std::fmt;
+
+use super::packet::{self, Request, Response};
+use super::FILE_PART_SIZE;
+use crate::record::Direction;
+
+/// Stateful decoder for the packets of a session in the order they were transferred.
+///
+/// The state is needed to tell the raw PIT and file data apart from the control packets.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    state: State,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Idle,
+    PitData {
+        part: u32,
+    },
+    PitFlash,
+    PitUpload,
+    FileData {
+        chunk: u32,
+        chunks: u32,
+    },
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, direction: Direction, data: &[u8]) -> Message {
+        if data.is_empty() {
+            return Message::ZeroLength;
+        }
+        match (direction, &mut self.state) {
+            (Direction::Out, _) if data == packet::HANDSHAKE_REQUEST => Message::Handshake("ODIN"),
+            (Direction::In, _) if data == packet::HANDSHAKE_RESPONSE => Message::Handshake("LOKE"),
+            (Direction::In, State::PitData { part }) => {
+                let part = *part;
+                self.state = State::Idle;
+                Message::PitData {
+                    part,
+                    size: data.len(),
+                }
+            }
+            (Direction::Out, State::PitUpload) => {
+                self.state = State::Idle;
+                Message::PitData {
+                    part: 0,
+                    size: data.len(),
+                }
+            }
+            (Direction::Out, State::FileData { chunk, chunks }) if *chunk < *chunks => {
+                *chunk += 1;
+                Message::FileData {
+                    chunk: *chunk - 1,
+                    size: data.len(),
+                }
+            }
+            (Direction::Out, _) => match Request::decode(data) {
+                Ok(request) => {
+                    self.state = match (&request, &self.state) {
+                        (Request::PitFlash, _) => State::PitFlash,
+                        (Request::PitPart { .. }, State::PitFlash) => State::PitUpload,
+                        (Request::PitPart { index }, _) => State::PitData { part: *index },
+                        (Request::FilePart { size }, _) => State::FileData {
+                            chunk: 0,
+                            chunks: size.div_ceil(FILE_PART_SIZE),
+                        },
+                        (_, State::PitFlash) => State::PitFlash,
+                        _ => State::Idle,
+                    };
+                    Message::Request(request)
+                }
+                Err(_) => Message::Unknown(data.to_vec()),
+            },
+            (Direction::In, _) => match Response::decode(data) {
+                Ok(response) => Message::Response(response),
+                Err(_) => Message::Unknown(data.to_vec()),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Handshake(&'static str),
+    Request(Request),
+    Response(Response),
+    PitData { part: u32, size: usize },
+    FileData { chunk: u32, size: usize },
+    ZeroLength,
+    Unknown(Vec<u8>),
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Handshake(magic) => write!(f, "HANDSHAKE {:?}", magic),
+            Message::Request(request) => request.fmt(f),
+            Message::Response(response) => response.fmt(f),
+            Message::PitData { part, size } => write!(f, "PIT DATA part={} size={}", part, size),
+            Message::FileData { chunk, size } => {
+                write!(f, "FILE_TRANSFER DATA chunk={} size={}", chunk, size)
+            }
+            Message::ZeroLength => f.write_str("ZERO-LENGTH PACKET"),
+            Message::Unknown(data) => {
+                let len = data.len().min(16);
+                write!(f, "UNKNOWN len={} {:02X?}", data.len(), &data[..len])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::packet::ResponseType;
+
+    fn out(decoder: &mut Decoder, request: Request) -> String {
+        decoder
+            .decode(Direction::Out, &request.encode())
+            .to_string()
+    }
+
+    #[test]
+    fn decode_pit_dump() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.decode(Direction::Out, b"ODIN").to_string(),
+            r#"HANDSHAKE "ODIN""#
+        );
+        assert_eq!(
+            decoder.decode(Direction::In, b"LOKE").to_string(),
+            r#"HANDSHAKE "LOKE""#
+        );
+        assert_eq!(out(&mut decoder, Request::PitDump), "PIT DUMP");
+        let response = Response {
+            kind: ResponseType::PitFile,
+            value: 600,
+        };
+        assert_eq!(
+            decoder
+                .decode(Direction::In, &response.encode())
+                .to_string(),
+            "RESPONSE PIT value=600"
+        );
+        assert_eq!(
+            out(&mut decoder, Request::PitPart { index: 1 }),
+            "PIT PART index=1"
+        );
+        // the answer to a part request is data, even if it looks like a response
+        assert_eq!(
+            decoder
+                .decode(Direction::In, &response.encode())
+                .to_string(),
+            "PIT DATA part=1 size=8"
+        );
+        assert_eq!(
+            decoder.decode(Direction::In, &[]).to_string(),
+            "ZERO-LENGTH PACKET"
+        );
+    }
+
+    #[test]
+    fn decode_pit_flash() {
+        let mut decoder = Decoder::new();
+        assert_eq!(out(&mut decoder, Request::PitFlash), "PIT FLASH");
+        assert_eq!(
+            out(&mut decoder, Request::PitPart { index: 1024 }),
+            "PIT PART index=1024"
+        );
+        assert_eq!(
+            decoder.decode(Direction::Out, &[0; 1024]).to_string(),
+            "PIT DATA part=0 size=1024"
+        );
+        assert_eq!(
+            out(&mut decoder, Request::PitEnd { size: 1024 }),
+            "PIT END size=1024"
+        );
+    }
+
+    #[test]
+    fn decode_file_data() {
+        let mut decoder = Decoder::new();
+        let size = FILE_PART_SIZE + 1;
+        assert_eq!(
+            out(&mut decoder, Request::FilePart { size }),
+            format!("FILE_TRANSFER PART size={}", size)
+        );
+        // file chunks are data, even if they look like requests
+        let chunk = Request::EndSession.encode();
+        assert_eq!(
+            decoder.decode(Direction::Out, &chunk).to_string(),
+            "FILE_TRANSFER DATA chunk=0 size=1024"
+        );
+        assert_eq!(
+            decoder.decode(Direction::Out, &chunk).to_string(),
+            "FILE_TRANSFER DATA chunk=1 size=1024"
+        );
+        assert_eq!(out(&mut decoder, Request::EndSession), "END_SESSION END");
+    }
+
+    #[test]
+    fn decode_unknown() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.decode(Direction::Out, &[1, 2, 3]).to_string(),
+            "UNKNOWN len=3 [01, 02, 03]"
+        );
+        assert_eq!(
+            decoder.decode(Direction::In, &[0x42; 8]).to_string(),
+            "UNKNOWN len=8 [42, 42, 42, 42, 42, 42, 42, 42]"
+        );
+    }
+}
@@ -3,24 +3,61 @@ use std::io::Error as IoError;
 
 use rusb::Error as UsbError;
 
+use super::packet::{Response, ResponseType};
+
 #[derive(Debug)]
 pub enum Error {
     Handshake,
+    InvalidPacket(Vec<u8>),
+    UnexpectedResponse {
+        expected: ResponseType,
+        actual: Response,
+    },
     Io(IoError),
     Usb(UsbError),
+    Replay(String),
+    Cancelled,
+    Sequence {
+        operation: &'static str,
+        state: String,
+    },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Handshake => f.write_str("handshake failed"),
+            Error::InvalidPacket(buf) => {
+                let len = buf.len().min(16);
+                write!(f, "invalid packet: {:02X?}", &buf[..len])
+            }
+            Error::UnexpectedResponse { expected, actual } => {
+                write!(
+                    f,
+                    "unexpected response: expected {}, got {}",
+                    expected, actual
+                )
+            }
             Error::Io(_) => f.write_str("io error"),
             Error::Usb(_) => f.write_str("usb error"),
+            Error::Replay(msg) => write!(f, "replay error: {}", msg),
+            Error::Cancelled => f.write_str("operation cancelled"),
+            Error::Sequence { operation, state } => {
+                write!(f, "cannot {} while {}", operation, state)
+            }
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Usb(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<IoError> for Error {
     fn from(e: IoError) -> Self {
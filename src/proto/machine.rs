@@ -0,0 +1,736 @@
+//! Sans-IO state machine of an Odin session.
+//!
+//! [`Machine`] holds the protocol logic without doing any I/O itself: an operation is
+//! started with one of its methods, then [`Machine::poll`] tells the driver which bulk
+//! transfer to do next and [`Machine::complete`] feeds the result back, until the
+//! operation finishes with an [`Event`]. The machine enforces the legal ordering of
+//! the operations, so the same logic can be driven by blocking code, async code or
+//! an event loop.
+use std::fmt;
+use std::mem;
+
+use super::packet::{self, FileEnd, Request, Response, ResponseType, REQUEST_SIZE, RESPONSE_SIZE};
+use super::util::{Batch, BatchIterator};
+use super::{Error, FileTarget, FILE_PART_SIZE, ODIN_VERSION};
+
+/// Size of the parts the PIT is received in.
+const PIT_PART_SIZE: usize = 500;
+
+/// Number of file parts sent in one batch.
+const CHUNKS_PER_BATCH: u32 = 30;
+
+/// Point reached in the session, which decides the operations allowed next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connected,
+    Handshaken,
+    Session,
+    PitReceived,
+    TotalSizeSent,
+    Ended,
+    Rebooting,
+    Failed,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connected => "connected",
+            Phase::Handshaken => "handshaken",
+            Phase::Session => "in session",
+            Phase::PitReceived => "pit received",
+            Phase::TotalSizeSent => "total size sent",
+            Phase::Ended => "session ended",
+            Phase::Rebooting => "rebooting",
+            Phase::Failed => "failed",
+        })
+    }
+}
+
+/// Next thing the driver of a [`Machine`] has to do.
+#[derive(Debug)]
+pub enum Action<'a> {
+    /// Write the bytes to the bulk out endpoint, then call [`Machine::complete`]
+    /// with the number of bytes written.
+    Write(&'a [u8]),
+    /// Read from the bulk in endpoint into the buffer, then call
+    /// [`Machine::complete`] with the number of bytes read.
+    Read(&'a mut [u8]),
+    /// Fill the buffer with the next part of the file being transferred, then call
+    /// [`Machine::complete`] with the number of bytes filled in.
+    Fill(&'a mut [u8]),
+    /// The operation finished.
+    Done(Event),
+    /// No operation is running.
+    Idle,
+}
+
+/// Outcome of a finished operation.
+#[derive(Debug)]
+pub enum Event {
+    Handshake,
+    SessionBegun {
+        default_packet_size: u32,
+    },
+    FilePartSizeSet,
+    PitReceived(Vec<u8>),
+    PitFlashed,
+    TotalSizeSent,
+    FileTransferred,
+    /// The file transfer was stopped with [`Machine::stop_transfer`] before all
+    /// batches were sent.
+    TransferStopped,
+    SessionEnded,
+    Rebooting,
+}
+
+#[derive(Debug)]
+enum Step {
+    Idle,
+    WriteHandshake,
+    ReadHandshake,
+    WriteRequest(Request),
+    ReadResponse(Request),
+    WritePitPart(u32),
+    ReadPitPart(u32),
+    ReadPitEnd,
+    WritePitData,
+    ReadPitDataResponse,
+    FillChunk(u32),
+    WriteChunk(u32),
+    ReadChunkResponse(u32),
+    WriteChunkEnd(u32),
+    Done(Event),
+}
+
+#[derive(Debug)]
+struct Transfer {
+    target: FileTarget,
+    batches: BatchIterator,
+    batch: Option<Batch>,
+    stop: bool,
+}
+
+/// Sans-IO state machine of an Odin session, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Machine {
+    phase: Phase,
+    step: Step,
+    packet: Vec<u8>,
+    chunk: Vec<u8>,
+    /// Number of file bytes in `chunk`, the rest is padding.
+    filled: usize,
+    pit: Vec<u8>,
+    transfer: Option<Transfer>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Machine {
+    /// Creates a machine for a freshly connected device.
+    pub fn new() -> Self {
+        Self::resume(Phase::Connected)
+    }
+
+    /// Creates a machine for a device already in the given phase.
+    pub(super) fn resume(phase: Phase) -> Self {
+        Self {
+            phase,
+            step: Step::Idle,
+            packet: vec![0; REQUEST_SIZE],
+            chunk: vec![],
+            filled: 0,
+            pit: vec![],
+            transfer: None,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Whether an operation is running.
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.step, Step::Idle | Step::Done(_))
+    }
+
+    pub fn handshake(&mut self) -> Result<(), Error> {
+        self.start("handshake", &[Phase::Connected], Step::WriteHandshake)
+    }
+
+    pub fn begin_session(&mut self) -> Result<(), Error> {
+        let request = Request::BeginSession {
+            version: ODIN_VERSION,
+        };
+        self.start_request("begin session", &[Phase::Handshaken, Phase::Ended], request)
+    }
+
+    pub fn setup_file_part_size(&mut self, size: u32) -> Result<(), Error> {
+        let request = Request::FilePartSize { size };
+        self.start_request("set up file part size", &[Phase::Session], request)
+    }
+
+    pub fn receive_pit(&mut self) -> Result<(), Error> {
+        self.start_request("receive pit", &[Phase::Session], Request::PitDump)
+    }
+
+    /// Starts flashing a new PIT, repartitioning the device.
+    pub fn flash_pit(&mut self, pit: Vec<u8>) -> Result<(), Error> {
+        self.start_request("flash pit", &[Phase::Session], Request::PitFlash)?;
+        self.pit = pit;
+        Ok(())
+    }
+
+    pub fn send_total_size(&mut self, size: u64) -> Result<(), Error> {
+        let request = Request::TotalBytes { size };
+        let phases = [Phase::Session, Phase::PitReceived];
+        self.start_request("send total size", &phases, request)
+    }
+
+    /// Starts the transfer of a file of `file_size` bytes to the given target.
+    ///
+    /// The file data is requested with [`Action::Fill`].
+    pub fn file_transfer(&mut self, target: &FileTarget, file_size: u64) -> Result<(), Error> {
+        self.start_request("transfer file", &[Phase::TotalSizeSent], Request::FileFlash)?;
+        self.transfer = Some(Transfer {
+            target: target.clone(),
+            batches: BatchIterator::new(file_size, FILE_PART_SIZE, CHUNKS_PER_BATCH),
+            batch: None,
+            stop: false,
+        });
+        Ok(())
+    }
+
+    /// Returns the file data of the chunk whose write just completed.
+    ///
+    /// Set from the completion of its [`Action::Write`] until the response of the
+    /// device is read, so a driver can follow the bytes actually sent.
+    pub fn sent_chunk(&self) -> Option<&[u8]> {
+        match self.step {
+            Step::ReadChunkResponse(_) => Some(&self.chunk[..self.filled]),
+            _ => None,
+        }
+    }
+
+    /// Stops the running file transfer once the current batch is completed.
+    ///
+    /// The session stays usable, the operation finishes with
+    /// [`Event::TransferStopped`] unless the last batch was already started.
+    pub fn stop_transfer(&mut self) {
+        if let Some(transfer) = &mut self.transfer {
+            transfer.stop = true;
+        }
+    }
+
+    pub fn end_session(&mut self) -> Result<(), Error> {
+        let phases = [Phase::Session, Phase::PitReceived, Phase::TotalSizeSent];
+        self.start_request("end session", &phases, Request::EndSession)
+    }
+
+    pub fn reboot(&mut self) -> Result<(), Error> {
+        let phases = [
+            Phase::Handshaken,
+            Phase::Session,
+            Phase::PitReceived,
+            Phase::TotalSizeSent,
+            Phase::Ended,
+        ];
+        self.start_request("reboot", &phases, Request::Reboot)
+    }
+
+    fn start_request(
+        &mut self,
+        operation: &'static str,
+        phases: &[Phase],
+        request: Request,
+    ) -> Result<(), Error> {
+        self.start(operation, phases, Step::WriteRequest(request))
+    }
+
+    fn start(
+        &mut self,
+        operation: &'static str,
+        phases: &[Phase],
+        step: Step,
+    ) -> Result<(), Error> {
+        if self.is_busy() {
+            return Err(Error::Sequence {
+                operation,
+                state: "busy".into(),
+            });
+        }
+        if !phases.contains(&self.phase) {
+            return Err(Error::Sequence {
+                operation,
+                state: self.phase.to_string(),
+            });
+        }
+        self.prepare(step);
+        Ok(())
+    }
+
+    /// Enters the step and sets up its buffer.
+    fn prepare(&mut self, step: Step) {
+        match &step {
+            Step::WriteRequest(request) => {
+                self.packet = request.encode();
+                tracing::debug!("out: {:X?}", &self.packet[..request.significant_len()]);
+            }
+            Step::WritePitPart(index) => {
+                self.packet = Request::PitPart { index: *index }.encode();
+                tracing::debug!("out: {:X?}", &self.packet[..16]);
+            }
+            Step::FillChunk(_) => {
+                self.chunk.resize(FILE_PART_SIZE as usize, 0);
+            }
+            Step::WriteChunk(_) => {
+                tracing::debug!("out: {:X?}", &self.chunk[..16]);
+            }
+            Step::WritePitData => {
+                tracing::debug!("out: {:X?}", &self.pit[..self.pit.len().min(16)]);
+            }
+            Step::ReadPitEnd => {
+                tracing::trace!("read bulk with empty slice");
+            }
+            Step::WriteChunkEnd(_) => {
+                tracing::trace!("write bulk with empty slice");
+            }
+            _ => {}
+        }
+        self.step = step;
+    }
+
+    /// Returns the next action of the running operation.
+    pub fn poll(&mut self) -> Action<'_> {
+        match &mut self.step {
+            Step::Idle => Action::Idle,
+            Step::WriteHandshake => Action::Write(packet::HANDSHAKE_REQUEST),
+            Step::ReadHandshake => Action::Read(&mut self.packet[..8]),
+            Step::WriteRequest(_) | Step::WritePitPart(_) => Action::Write(&self.packet),
+            Step::ReadResponse(_) => Action::Read(&mut self.packet[..RESPONSE_SIZE]),
+            Step::ReadPitPart(index) => {
+                let start = *index as usize * PIT_PART_SIZE;
+                let end = (start + PIT_PART_SIZE).min(self.pit.len());
+                Action::Read(&mut self.pit[start..end])
+            }
+            Step::ReadPitEnd => Action::Read(&mut []),
+            Step::WritePitData => Action::Write(&self.pit),
+            Step::ReadPitDataResponse => Action::Read(&mut self.packet[..RESPONSE_SIZE]),
+            Step::FillChunk(_) => Action::Fill(&mut self.chunk),
+            Step::WriteChunk(_) => Action::Write(&self.chunk),
+            Step::ReadChunkResponse(_) => Action::Read(&mut self.packet[..RESPONSE_SIZE]),
+            Step::WriteChunkEnd(_) => Action::Write(&[]),
+            Step::Done(_) => match mem::replace(&mut self.step, Step::Idle) {
+                Step::Done(event) => Action::Done(event),
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    /// Completes the last action returned by [`poll`](Self::poll) with the number of
+    /// bytes transferred.
+    ///
+    /// An error fails the machine, no further operations are possible.
+    pub fn complete(&mut self, n: usize) -> Result<(), Error> {
+        let result = self.advance(n);
+        if result.is_err() {
+            self.fail();
+        }
+        result
+    }
+
+    /// Aborts the running operation.
+    ///
+    /// The device is left in an unknown state, so the machine fails.
+    pub fn abort(&mut self) {
+        if self.is_busy() {
+            self.fail();
+        }
+    }
+
+    fn fail(&mut self) {
+        self.phase = Phase::Failed;
+        self.step = Step::Idle;
+        self.transfer = None;
+    }
+
+    fn advance(&mut self, n: usize) -> Result<(), Error> {
+        let next = match mem::replace(&mut self.step, Step::Idle) {
+            Step::Idle | Step::Done(_) => {
+                return Err(Error::Sequence {
+                    operation: "complete",
+                    state: "idle".into(),
+                })
+            }
+            Step::WriteHandshake => Step::ReadHandshake,
+            Step::ReadHandshake => {
+                if n != 4 || &self.packet[..4] != packet::HANDSHAKE_RESPONSE {
+                    return Err(Error::Handshake);
+                }
+                self.phase = Phase::Handshaken;
+                Step::Done(Event::Handshake)
+            }
+            Step::WriteRequest(request) => Step::ReadResponse(request),
+            Step::ReadResponse(request) => {
+                tracing::debug!("in:  {:X?}", &self.packet[..n]);
+                let value = Response::decode(&self.packet[..n])?.expect(request.response_type())?;
+                self.handle_response(request, value)
+            }
+            Step::WritePitPart(index) => Step::ReadPitPart(index),
+            Step::ReadPitPart(index) => {
+                let start = index as usize * PIT_PART_SIZE;
+                tracing::debug!(n, "in:  {:X?}", &self.pit[start..start + n.min(16)]);
+                self.next_pit_part(index + 1)
+            }
+            Step::ReadPitEnd => {
+                tracing::debug!("end pit transfer");
+                Step::WriteRequest(Request::PitEnd { size: 0 })
+            }
+            Step::WritePitData => Step::ReadPitDataResponse,
+            Step::ReadPitDataResponse => {
+                tracing::debug!("in:  {:X?}", &self.packet[..n]);
+                Response::decode(&self.packet[..n])?.expect(ResponseType::PitFile)?;
+                Step::WriteRequest(Request::PitEnd {
+                    size: self.pit.len() as u32,
+                })
+            }
+            Step::FillChunk(index) => {
+                self.chunk[n..].iter_mut().for_each(|b| *b = 0);
+                self.filled = n;
+                Step::WriteChunk(index)
+            }
+            Step::WriteChunk(index) => Step::ReadChunkResponse(index),
+            Step::ReadChunkResponse(index) => {
+                tracing::debug!("in:  {:X?}", &self.packet[..n]);
+                let response = Response::decode(&self.packet[..n])?;
+                if response.expect(ResponseType::FilePart)? != index {
+                    return Err(Error::UnexpectedResponse {
+                        expected: ResponseType::FilePart,
+                        actual: response,
+                    });
+                }
+                Step::WriteChunkEnd(index)
+            }
+            Step::WriteChunkEnd(index) => self.next_chunk(index + 1),
+        };
+        self.prepare(next);
+        Ok(())
+    }
+
+    fn handle_response(&mut self, request: Request, value: u32) -> Step {
+        match request {
+            Request::BeginSession { .. } => {
+                tracing::debug!(default_packet_size = value);
+                self.phase = Phase::Session;
+                Step::Done(Event::SessionBegun {
+                    default_packet_size: value,
+                })
+            }
+            Request::FilePartSize { .. } => {
+                tracing::debug!(result = value);
+                Step::Done(Event::FilePartSizeSet)
+            }
+            Request::TotalBytes { .. } => {
+                self.phase = Phase::TotalSizeSent;
+                Step::Done(Event::TotalSizeSent)
+            }
+            Request::PitDump => {
+                tracing::debug!(pit_size = value);
+                self.pit = vec![0; value as usize];
+                self.next_pit_part(0)
+            }
+            Request::PitEnd { size: 0 } => {
+                self.phase = Phase::PitReceived;
+                Step::Done(Event::PitReceived(mem::take(&mut self.pit)))
+            }
+            Request::PitFlash => Step::WriteRequest(Request::PitPart {
+                index: self.pit.len() as u32,
+            }),
+            Request::PitPart { .. } => Step::WritePitData,
+            Request::PitEnd { .. } => {
+                self.pit.clear();
+                Step::Done(Event::PitFlashed)
+            }
+            Request::FileFlash | Request::FileEnd(_) => self.next_batch(),
+            Request::FilePart { .. } => self.next_chunk(0),
+            Request::EndSession => {
+                self.phase = Phase::Ended;
+                Step::Done(Event::SessionEnded)
+            }
+            Request::Reboot => {
+                self.phase = Phase::Rebooting;
+                Step::Done(Event::Rebooting)
+            }
+            Request::DeviceType | Request::EnableTFlash { .. } => {
+                unreachable!("request {:?} is never started", request)
+            }
+        }
+    }
+
+    fn next_pit_part(&mut self, index: u32) -> Step {
+        if (index as usize) * PIT_PART_SIZE < self.pit.len() {
+            Step::WritePitPart(index)
+        } else {
+            Step::ReadPitEnd
+        }
+    }
+
+    fn next_batch(&mut self) -> Step {
+        let transfer = self.transfer.as_mut().expect("file transfer state");
+        if transfer.stop && !transfer.batch.as_ref().is_some_and(Batch::is_last) {
+            self.transfer = None;
+            return Step::Done(Event::TransferStopped);
+        }
+        match transfer.batches.next() {
+            Some(batch) => {
+                let request = Request::FilePart { size: batch.size() };
+                transfer.batch = Some(batch);
+                Step::WriteRequest(request)
+            }
+            None => {
+                self.transfer = None;
+                Step::Done(Event::FileTransferred)
+            }
+        }
+    }
+
+    fn next_chunk(&mut self, index: u32) -> Step {
+        let transfer = self.transfer.as_ref().expect("file transfer state");
+        let batch = transfer.batch.as_ref().expect("file batch state");
+        if index < batch.chunks() {
+            Step::FillChunk(index)
+        } else {
+            Step::WriteRequest(Request::FileEnd(FileEnd {
+                target: transfer.target.clone(),
+                size: batch.effective_size(),
+                eof: batch.is_last(),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expects the machine to write the request.
+    fn write_request(machine: &mut Machine, expected: Request) {
+        let n = match machine.poll() {
+            Action::Write(buf) => {
+                assert_eq!(Request::decode(buf).unwrap(), expected);
+                buf.len()
+            }
+            action => panic!("expected write of {}, got {:?}", expected, action),
+        };
+        machine.complete(n).unwrap();
+    }
+
+    fn write(machine: &mut Machine, expected: &[u8]) {
+        match machine.poll() {
+            Action::Write(buf) => assert_eq!(buf, expected),
+            action => panic!("expected write, got {:?}", action),
+        }
+        machine.complete(expected.len()).unwrap();
+    }
+
+    /// Lets the device answer the read of the machine with `data`.
+    fn read(machine: &mut Machine, data: &[u8]) -> Result<(), Error> {
+        match machine.poll() {
+            Action::Read(buf) => buf[..data.len()].copy_from_slice(data),
+            action => panic!("expected read, got {:?}", action),
+        }
+        machine.complete(data.len())
+    }
+
+    fn respond(machine: &mut Machine, kind: ResponseType, value: u32) {
+        read(machine, &Response { kind, value }.encode()).unwrap();
+    }
+
+    fn done(machine: &mut Machine) -> Event {
+        match machine.poll() {
+            Action::Done(event) => event,
+            action => panic!("expected done, got {:?}", action),
+        }
+    }
+
+    fn session(machine: &mut Machine) {
+        machine.handshake().unwrap();
+        write(machine, b"ODIN");
+        read(machine, b"LOKE").unwrap();
+        assert!(matches!(done(machine), Event::Handshake));
+
+        machine.begin_session().unwrap();
+        write_request(machine, Request::BeginSession { version: 4 });
+        respond(machine, ResponseType::Session, 0x100000);
+        assert!(matches!(
+            done(machine),
+            Event::SessionBegun {
+                default_packet_size: 0x100000
+            }
+        ));
+        assert_eq!(machine.phase(), Phase::Session);
+    }
+
+    fn assert_sequence_error(result: Result<(), Error>, expected_state: &str) {
+        match result {
+            Err(Error::Sequence { state, .. }) => assert_eq!(state, expected_state),
+            result => panic!("expected sequence error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn flash_sequence() {
+        let mut machine = Machine::new();
+        session(&mut machine);
+
+        machine.receive_pit().unwrap();
+        write_request(&mut machine, Request::PitDump);
+        respond(&mut machine, ResponseType::PitFile, 600);
+        write_request(&mut machine, Request::PitPart { index: 0 });
+        read(&mut machine, &[1; 500]).unwrap();
+        write_request(&mut machine, Request::PitPart { index: 1 });
+        read(&mut machine, &[2; 100]).unwrap();
+        read(&mut machine, &[]).unwrap();
+        write_request(&mut machine, Request::PitEnd { size: 0 });
+        respond(&mut machine, ResponseType::PitFile, 0);
+        match done(&mut machine) {
+            Event::PitReceived(pit) => {
+                assert_eq!(pit.len(), 600);
+                assert_eq!(pit[499..501], [1, 2]);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_sequence_error(machine.receive_pit(), "pit received");
+
+        machine.send_total_size(10).unwrap();
+        write_request(&mut machine, Request::TotalBytes { size: 10 });
+        respond(&mut machine, ResponseType::Session, 0);
+        assert!(matches!(done(&mut machine), Event::TotalSizeSent));
+        assert_eq!(machine.phase(), Phase::TotalSizeSent);
+
+        let target = FileTarget::ApplicationProcessor {
+            device_type: 2,
+            identifier: 81,
+        };
+        machine.file_transfer(&target, 10).unwrap();
+        write_request(&mut machine, Request::FileFlash);
+        respond(&mut machine, ResponseType::FileTransfer, 0);
+        write_request(
+            &mut machine,
+            Request::FilePart {
+                size: FILE_PART_SIZE,
+            },
+        );
+        respond(&mut machine, ResponseType::FileTransfer, 0);
+        match machine.poll() {
+            Action::Fill(buf) => {
+                assert_eq!(buf.len(), FILE_PART_SIZE as usize);
+                buf[..10].copy_from_slice(b"0123456789");
+            }
+            action => panic!("expected fill, got {:?}", action),
+        }
+        machine.complete(10).unwrap();
+        match machine.poll() {
+            Action::Write(buf) => {
+                assert_eq!(buf.len(), FILE_PART_SIZE as usize);
+                assert_eq!(&buf[..10], b"0123456789");
+                assert!(buf[10..].iter().all(|&b| b == 0));
+            }
+            action => panic!("expected write, got {:?}", action),
+        }
+        assert_eq!(machine.sent_chunk(), None);
+        machine.complete(FILE_PART_SIZE as usize).unwrap();
+        assert_eq!(machine.sent_chunk(), Some(&b"0123456789"[..]));
+        respond(&mut machine, ResponseType::FilePart, 0);
+        assert_eq!(machine.sent_chunk(), None);
+        write(&mut machine, &[]);
+        write_request(
+            &mut machine,
+            Request::FileEnd(FileEnd {
+                target,
+                size: 10,
+                eof: true,
+            }),
+        );
+        respond(&mut machine, ResponseType::FileTransfer, 0);
+        assert!(matches!(done(&mut machine), Event::FileTransferred));
+
+        machine.end_session().unwrap();
+        write_request(&mut machine, Request::EndSession);
+        respond(&mut machine, ResponseType::EndSession, 0);
+        assert!(matches!(done(&mut machine), Event::SessionEnded));
+
+        machine.reboot().unwrap();
+        write_request(&mut machine, Request::Reboot);
+        respond(&mut machine, ResponseType::EndSession, 0);
+        assert!(matches!(done(&mut machine), Event::Rebooting));
+        assert_eq!(machine.phase(), Phase::Rebooting);
+        assert!(matches!(machine.poll(), Action::Idle));
+    }
+
+    #[test]
+    fn flash_pit_sequence() {
+        let mut machine = Machine::new();
+        session(&mut machine);
+
+        machine.flash_pit(vec![7; 300]).unwrap();
+        write_request(&mut machine, Request::PitFlash);
+        respond(&mut machine, ResponseType::PitFile, 0);
+        write_request(&mut machine, Request::PitPart { index: 300 });
+        respond(&mut machine, ResponseType::PitFile, 0);
+        write(&mut machine, &[7; 300]);
+        respond(&mut machine, ResponseType::PitFile, 0);
+        write_request(&mut machine, Request::PitEnd { size: 300 });
+        respond(&mut machine, ResponseType::PitFile, 0);
+        assert!(matches!(done(&mut machine), Event::PitFlashed));
+        assert_eq!(machine.phase(), Phase::Session);
+    }
+
+    #[test]
+    fn reject_out_of_phase_operations() {
+        let mut machine = Machine::new();
+        let target = FileTarget::CommunicationProcessor { device_type: 1 };
+
+        assert_sequence_error(machine.begin_session(), "connected");
+        assert_sequence_error(machine.receive_pit(), "connected");
+        assert_sequence_error(machine.file_transfer(&target, 10), "connected");
+        assert!(matches!(machine.poll(), Action::Idle));
+
+        session(&mut machine);
+        assert_sequence_error(machine.handshake(), "in session");
+        assert_sequence_error(machine.file_transfer(&target, 10), "in session");
+
+        machine.end_session().unwrap();
+        assert_sequence_error(machine.send_total_size(10), "busy");
+    }
+
+    #[test]
+    fn fail_on_unexpected_response() {
+        let mut machine = Machine::new();
+        session(&mut machine);
+
+        machine.send_total_size(10).unwrap();
+        write_request(&mut machine, Request::TotalBytes { size: 10 });
+        let response = Response {
+            kind: ResponseType::PitFile,
+            value: 0,
+        };
+        let err = read(&mut machine, &response.encode()).unwrap_err();
+        assert!(matches!(err, Error::UnexpectedResponse { .. }), "{:?}", err);
+        assert_eq!(machine.phase(), Phase::Failed);
+        assert_sequence_error(machine.end_session(), "failed");
+    }
+
+    #[test]
+    fn fail_on_bad_handshake() {
+        let mut machine = Machine::new();
+        machine.handshake().unwrap();
+        write(&mut machine, b"ODIN");
+        assert!(matches!(read(&mut machine, b"NOPE"), Err(Error::Handshake)));
+        assert_eq!(machine.phase(), Phase::Failed);
+    }
+}
@@ -0,0 +1,585 @@
+//! Wire format of the Odin protocol packets.
+//!
+//! Requests are sent as 1024 byte packets starting with the control type and the
+//! request type, responses are 8 byte packets with the response type and a value.
+//! All integers are little endian.
+use std::convert::TryInto;
+use std::fmt;
+
+use super::Error;
+
+macro_rules! consts {
+    ($($(#[$outer:meta])* const $name:ident = $value:expr;)+) => {
+        $($(#[$outer])* const $name: [u8; 4] = u32::to_le_bytes($value);)*
+    }
+}
+
+consts! {
+    const CONTROL_TYPE_SESSION = 0x64;
+    const CONTROL_TYPE_PIT_FILE = 0x65;
+    const CONTROL_TYPE_FILE_TRANSFER = 0x66;
+    const CONTROL_TYPE_END_SESSION= 0x67;
+
+    const SESSION_REQUEST_TYPE_BEGIN_SESSION = 0x00;
+    const SESSION_REQUEST_TYPE_DEVICE_TYPE = 0x01;
+    const SESSION_REQUEST_TYPE_TOTAL_BYTES = 0x02;
+    const SESSION_REQUEST_TYPE_FILE_PART_SIZE = 0x05;
+    const SESSION_REQUEST_TYPE_ENABLE_TFLASH = 0x08;
+
+    const END_SESSION_REQUEST_TYPE_END_SESSION = 0x00;
+    const END_SESSION_REQUEST_TYPE_REBOOT = 0x01;
+
+    const FILE_REQUEST_TYPE_FLASH = 0x00;
+    const FILE_REQUEST_TYPE_PART = 0x02;
+    const FILE_REQUEST_TYPE_END_TRANSFER = 0x03;
+
+    const FILE_END_TRANSFER_DEST_PHONE = 0x00;
+    const FILE_END_TRANSFER_DEST_MODEM = 0x01;
+
+    const PIT_REQUEST_TYPE_FLASH = 0x00;
+    const PIT_REQUEST_TYPE_DUMP = 0x01;
+    const PIT_REQUEST_TYPE_PART = 0x02;
+    const PIT_REQUEST_TYPE_END_TRANSFER = 0x03;
+
+    const RESPONSE_TYPE_SEND_FILE_PART = 0x00;
+    const RESPONSE_TYPE_SETUP_SESSION = 0x64;
+    const RESPONSE_TYPE_PIT_FILE = 0x65;
+    const RESPONSE_TYPE_FILE_TRANSFER = 0x66;
+    const RESPONSE_TYPE_END_SESSION = 0x67;
+}
+
+pub const HANDSHAKE_REQUEST: &[u8; 4] = b"ODIN";
+pub const HANDSHAKE_RESPONSE: &[u8; 4] = b"LOKE";
+
+pub const REQUEST_SIZE: usize = 1024;
+pub const RESPONSE_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    BeginSession {
+        version: u32,
+    },
+    DeviceType,
+    TotalBytes {
+        size: u64,
+    },
+    FilePartSize {
+        size: u32,
+    },
+    EnableTFlash {
+        enable: u32,
+    },
+    PitFlash,
+    PitDump,
+    /// Requests a part of the PIT when dumping, announces the size of the PIT when
+    /// flashing.
+    PitPart {
+        index: u32,
+    },
+    /// Ends the PIT transfer, the size is `0` when dumping.
+    PitEnd {
+        size: u32,
+    },
+    FileFlash,
+    FilePart {
+        size: u32,
+    },
+    FileEnd(FileEnd),
+    EndSession,
+    Reboot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEnd {
+    pub target: FileTarget,
+    pub size: u32,
+    pub eof: bool,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum FileTarget {
+    ApplicationProcessor { device_type: u32, identifier: u32 },
+    CommunicationProcessor { device_type: u32 },
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; REQUEST_SIZE];
+        let (control, request) = self.types();
+        buf[0..4].copy_from_slice(&control);
+        buf[4..8].copy_from_slice(&request);
+
+        match self {
+            Request::BeginSession { version } => buf[8..12].copy_from_slice(&version.to_le_bytes()),
+            Request::TotalBytes { size } => buf[8..16].copy_from_slice(&size.to_le_bytes()),
+            Request::FilePartSize { size } | Request::FilePart { size } => {
+                buf[8..12].copy_from_slice(&size.to_le_bytes())
+            }
+            Request::EnableTFlash { enable } => buf[8..12].copy_from_slice(&enable.to_le_bytes()),
+            Request::PitPart { index } => buf[8..12].copy_from_slice(&index.to_le_bytes()),
+            Request::PitEnd { size } => buf[8..12].copy_from_slice(&size.to_le_bytes()),
+            Request::FileEnd(FileEnd { target, size, eof }) => {
+                buf[12..16].copy_from_slice(&size.to_le_bytes());
+                buf[16..20].copy_from_slice(&u32::to_le_bytes(0)); // unknown1
+                match target {
+                    FileTarget::ApplicationProcessor {
+                        device_type,
+                        identifier,
+                    } => {
+                        buf[8..12].copy_from_slice(&FILE_END_TRANSFER_DEST_PHONE);
+
+                        buf[20..24].copy_from_slice(&device_type.to_le_bytes());
+                        buf[24..28].copy_from_slice(&identifier.to_le_bytes());
+                        buf[28..32].copy_from_slice(&u32::from(*eof).to_le_bytes());
+                    }
+                    FileTarget::CommunicationProcessor { device_type } => {
+                        buf[8..12].copy_from_slice(&FILE_END_TRANSFER_DEST_MODEM);
+
+                        buf[20..24].copy_from_slice(&device_type.to_le_bytes());
+                        buf[24..28].copy_from_slice(&u32::from(*eof).to_le_bytes());
+                    }
+                }
+            }
+            Request::DeviceType
+            | Request::PitFlash
+            | Request::PitDump
+            | Request::FileFlash
+            | Request::EndSession
+            | Request::Reboot => {}
+        }
+        buf
+    }
+
+    /// Decodes a request packet.
+    ///
+    /// Missing bytes of truncated packets (ex: from logs) are treated as zeros.
+    pub fn decode(buf: &[u8]) -> Result<Request, Error> {
+        if buf.len() < 8 {
+            return Err(Error::InvalidPacket(buf.to_vec()));
+        }
+        let control: [u8; 4] = buf[0..4].try_into().unwrap();
+        let request: [u8; 4] = buf[4..8].try_into().unwrap();
+
+        let req = match (control, request) {
+            (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_BEGIN_SESSION) => Request::BeginSession {
+                version: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_DEVICE_TYPE) => Request::DeviceType,
+            (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_TOTAL_BYTES) => Request::TotalBytes {
+                size: u64::from(u32_at(buf, 8)) | u64::from(u32_at(buf, 12)) << 32,
+            },
+            (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_FILE_PART_SIZE) => Request::FilePartSize {
+                size: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_ENABLE_TFLASH) => Request::EnableTFlash {
+                enable: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_FLASH) => Request::PitFlash,
+            (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_DUMP) => Request::PitDump,
+            (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_PART) => Request::PitPart {
+                index: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_END_TRANSFER) => Request::PitEnd {
+                size: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_FLASH) => Request::FileFlash,
+            (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_PART) => Request::FilePart {
+                size: u32_at(buf, 8),
+            },
+            (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_END_TRANSFER) => {
+                let dest = u32_at(buf, 8).to_le_bytes();
+                let size = u32_at(buf, 12);
+                let device_type = u32_at(buf, 20);
+                let (target, eof) = match dest {
+                    FILE_END_TRANSFER_DEST_PHONE => {
+                        let target = FileTarget::ApplicationProcessor {
+                            device_type,
+                            identifier: u32_at(buf, 24),
+                        };
+                        (target, u32_at(buf, 28))
+                    }
+                    FILE_END_TRANSFER_DEST_MODEM => {
+                        let target = FileTarget::CommunicationProcessor { device_type };
+                        (target, u32_at(buf, 24))
+                    }
+                    _ => return Err(Error::InvalidPacket(buf.to_vec())),
+                };
+                Request::FileEnd(FileEnd {
+                    target,
+                    size,
+                    eof: eof != 0,
+                })
+            }
+            (CONTROL_TYPE_END_SESSION, END_SESSION_REQUEST_TYPE_END_SESSION) => Request::EndSession,
+            (CONTROL_TYPE_END_SESSION, END_SESSION_REQUEST_TYPE_REBOOT) => Request::Reboot,
+            _ => return Err(Error::InvalidPacket(buf.to_vec())),
+        };
+        Ok(req)
+    }
+
+    /// The type of the response the device answers the request with.
+    pub fn response_type(&self) -> ResponseType {
+        match self.types().0 {
+            CONTROL_TYPE_SESSION => ResponseType::Session,
+            CONTROL_TYPE_PIT_FILE => ResponseType::PitFile,
+            CONTROL_TYPE_FILE_TRANSFER => ResponseType::FileTransfer,
+            _ => ResponseType::EndSession,
+        }
+    }
+
+    /// Number of leading bytes of the encoded packet that carry data.
+    pub fn significant_len(&self) -> usize {
+        match self {
+            Request::FileEnd(_) => 32,
+            _ => 16,
+        }
+    }
+
+    fn types(&self) -> ([u8; 4], [u8; 4]) {
+        match self {
+            Request::BeginSession { .. } => {
+                (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_BEGIN_SESSION)
+            }
+            Request::DeviceType => (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_DEVICE_TYPE),
+            Request::TotalBytes { .. } => (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_TOTAL_BYTES),
+            Request::FilePartSize { .. } => {
+                (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_FILE_PART_SIZE)
+            }
+            Request::EnableTFlash { .. } => {
+                (CONTROL_TYPE_SESSION, SESSION_REQUEST_TYPE_ENABLE_TFLASH)
+            }
+            Request::PitFlash => (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_FLASH),
+            Request::PitDump => (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_DUMP),
+            Request::PitPart { .. } => (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_PART),
+            Request::PitEnd { .. } => (CONTROL_TYPE_PIT_FILE, PIT_REQUEST_TYPE_END_TRANSFER),
+            Request::FileFlash => (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_FLASH),
+            Request::FilePart { .. } => (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_PART),
+            Request::FileEnd(_) => (CONTROL_TYPE_FILE_TRANSFER, FILE_REQUEST_TYPE_END_TRANSFER),
+            Request::EndSession => (
+                CONTROL_TYPE_END_SESSION,
+                END_SESSION_REQUEST_TYPE_END_SESSION,
+            ),
+            Request::Reboot => (CONTROL_TYPE_END_SESSION, END_SESSION_REQUEST_TYPE_REBOOT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseType {
+    /// Acknowledges a file part with its index.
+    FilePart,
+    Session,
+    PitFile,
+    FileTransfer,
+    EndSession,
+}
+
+impl ResponseType {
+    fn to_bytes(self) -> [u8; 4] {
+        match self {
+            ResponseType::FilePart => RESPONSE_TYPE_SEND_FILE_PART,
+            ResponseType::Session => RESPONSE_TYPE_SETUP_SESSION,
+            ResponseType::PitFile => RESPONSE_TYPE_PIT_FILE,
+            ResponseType::FileTransfer => RESPONSE_TYPE_FILE_TRANSFER,
+            ResponseType::EndSession => RESPONSE_TYPE_END_SESSION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response {
+    pub kind: ResponseType,
+    pub value: u32,
+}
+
+impl Response {
+    pub fn encode(&self) -> [u8; RESPONSE_SIZE] {
+        let mut buf = [0; RESPONSE_SIZE];
+        buf[0..4].copy_from_slice(&self.kind.to_bytes());
+        buf[4..8].copy_from_slice(&self.value.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Response, Error> {
+        if buf.len() != RESPONSE_SIZE {
+            return Err(Error::InvalidPacket(buf.to_vec()));
+        }
+        let kind = match buf[0..4].try_into().unwrap() {
+            RESPONSE_TYPE_SEND_FILE_PART => ResponseType::FilePart,
+            RESPONSE_TYPE_SETUP_SESSION => ResponseType::Session,
+            RESPONSE_TYPE_PIT_FILE => ResponseType::PitFile,
+            RESPONSE_TYPE_FILE_TRANSFER => ResponseType::FileTransfer,
+            RESPONSE_TYPE_END_SESSION => ResponseType::EndSession,
+            _ => return Err(Error::InvalidPacket(buf.to_vec())),
+        };
+        Ok(Response {
+            kind,
+            value: u32_at(buf, 4),
+        })
+    }
+
+    /// Returns the value of the response if it's of the `expected` type.
+    pub fn expect(self, expected: ResponseType) -> Result<u32, Error> {
+        if self.kind == expected {
+            Ok(self.value)
+        } else {
+            Err(Error::UnexpectedResponse {
+                expected,
+                actual: self,
+            })
+        }
+    }
+}
+
+impl fmt::Debug for FileTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileTarget::ApplicationProcessor {
+                device_type,
+                identifier,
+            } => f
+                .debug_struct("AP")
+                .field("device_type", device_type)
+                .field("identifier", identifier)
+                .finish(),
+            FileTarget::CommunicationProcessor { device_type } => f
+                .debug_struct("CP")
+                .field("device_type", device_type)
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::BeginSession { version } => write!(f, "SESSION BEGIN version={}", version),
+            Request::DeviceType => f.write_str("SESSION DEVICE_TYPE"),
+            Request::TotalBytes { size } => write!(f, "SESSION TOTAL_BYTES size={}", size),
+            Request::FilePartSize { size } => write!(f, "SESSION FILE_PART_SIZE size={}", size),
+            Request::EnableTFlash { enable } => {
+                write!(f, "SESSION ENABLE_TFLASH enable={}", enable)
+            }
+            Request::PitFlash => f.write_str("PIT FLASH"),
+            Request::PitDump => f.write_str("PIT DUMP"),
+            Request::PitPart { index } => write!(f, "PIT PART index={}", index),
+            Request::PitEnd { size } => write!(f, "PIT END size={}", size),
+            Request::FileFlash => f.write_str("FILE_TRANSFER FLASH"),
+            Request::FilePart { size } => write!(f, "FILE_TRANSFER PART size={}", size),
+            Request::FileEnd(FileEnd { target, size, eof }) => match target {
+                FileTarget::ApplicationProcessor {
+                    device_type,
+                    identifier,
+                } => write!(
+                    f,
+                    "FILE_TRANSFER END dest=PHONE device_type={} identifier={} size={} eof={}",
+                    device_type,
+                    identifier,
+                    size,
+                    u8::from(*eof)
+                ),
+                FileTarget::CommunicationProcessor { device_type } => write!(
+                    f,
+                    "FILE_TRANSFER END dest=MODEM device_type={} size={} eof={}",
+                    device_type,
+                    size,
+                    u8::from(*eof)
+                ),
+            },
+            Request::EndSession => f.write_str("END_SESSION END"),
+            Request::Reboot => f.write_str("END_SESSION REBOOT"),
+        }
+    }
+}
+
+impl fmt::Display for ResponseType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseType::FilePart => f.write_str("FILE_PART"),
+            ResponseType::Session => f.write_str("SESSION"),
+            ResponseType::PitFile => f.write_str("PIT"),
+            ResponseType::FileTransfer => f.write_str("FILE_TRANSFER"),
+            ResponseType::EndSession => f.write_str("END_SESSION"),
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RESPONSE {} value={}", self.kind, self.value)
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    if let Some(src) = buf.get(offset..) {
+        let n = src.len().min(4);
+        bytes[..n].copy_from_slice(&src[..n]);
+    }
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes the request and checks the layout of the packet.
+    fn encoded(request: &Request, control: u32, request_type: u32) -> Vec<u8> {
+        let buf = request.encode();
+        assert_eq!(buf.len(), REQUEST_SIZE, "{}", request);
+        assert_eq!(u32_at(&buf, 0), control, "{}", request);
+        assert_eq!(u32_at(&buf, 4), request_type, "{}", request);
+        assert_eq!(Request::decode(&buf).unwrap(), *request);
+        buf
+    }
+
+    /// Checks that the packet is padded with zeros from `end` on.
+    fn assert_zeros_from(buf: &[u8], end: usize) {
+        assert!(buf[end..].iter().all(|&b| b == 0), "{:02X?}", &buf[..32]);
+    }
+
+    #[test]
+    fn encode_session_requests() {
+        let buf = encoded(&Request::BeginSession { version: 4 }, 0x64, 0x00);
+        assert_eq!(buf[8..12], [4, 0, 0, 0]);
+        assert_zeros_from(&buf, 12);
+
+        let buf = encoded(&Request::DeviceType, 0x64, 0x01);
+        assert_zeros_from(&buf, 8);
+
+        let size = 0x0102_0304_0506_0708;
+        let buf = encoded(&Request::TotalBytes { size }, 0x64, 0x02);
+        assert_eq!(buf[8..16], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_zeros_from(&buf, 16);
+
+        let buf = encoded(&Request::FilePartSize { size: 0x100000 }, 0x64, 0x05);
+        assert_eq!(buf[8..12], [0, 0, 0x10, 0]);
+        assert_zeros_from(&buf, 12);
+
+        let buf = encoded(&Request::EnableTFlash { enable: 1 }, 0x64, 0x08);
+        assert_eq!(buf[8..12], [1, 0, 0, 0]);
+        assert_zeros_from(&buf, 12);
+    }
+
+    #[test]
+    fn encode_pit_requests() {
+        let buf = encoded(&Request::PitFlash, 0x65, 0x00);
+        assert_zeros_from(&buf, 8);
+
+        let buf = encoded(&Request::PitDump, 0x65, 0x01);
+        assert_zeros_from(&buf, 8);
+
+        let buf = encoded(&Request::PitPart { index: 0x0201 }, 0x65, 0x02);
+        assert_eq!(buf[8..12], [1, 2, 0, 0]);
+        assert_zeros_from(&buf, 12);
+
+        let buf = encoded(&Request::PitEnd { size: 4096 }, 0x65, 0x03);
+        assert_eq!(buf[8..12], [0, 0x10, 0, 0]);
+        assert_zeros_from(&buf, 12);
+    }
+
+    #[test]
+    fn encode_file_requests() {
+        let buf = encoded(&Request::FileFlash, 0x66, 0x00);
+        assert_zeros_from(&buf, 8);
+
+        let buf = encoded(&Request::FilePart { size: 0x1e00000 }, 0x66, 0x02);
+        assert_eq!(buf[8..12], [0, 0, 0xe0, 0x01]);
+        assert_zeros_from(&buf, 12);
+
+        let request = Request::FileEnd(FileEnd {
+            target: FileTarget::ApplicationProcessor {
+                device_type: 2,
+                identifier: 81,
+            },
+            size: 0x030201,
+            eof: true,
+        });
+        let buf = encoded(&request, 0x66, 0x03);
+        assert_eq!(u32_at(&buf, 8), 0, "destination phone");
+        assert_eq!(buf[12..16], [1, 2, 3, 0]);
+        assert_eq!(u32_at(&buf, 16), 0);
+        assert_eq!(u32_at(&buf, 20), 2);
+        assert_eq!(u32_at(&buf, 24), 81);
+        assert_eq!(u32_at(&buf, 28), 1);
+        assert_zeros_from(&buf, 32);
+
+        let request = Request::FileEnd(FileEnd {
+            target: FileTarget::CommunicationProcessor { device_type: 1 },
+            size: 512,
+            eof: false,
+        });
+        let buf = encoded(&request, 0x66, 0x03);
+        assert_eq!(u32_at(&buf, 8), 1, "destination modem");
+        assert_eq!(u32_at(&buf, 12), 512);
+        assert_eq!(u32_at(&buf, 20), 1);
+        assert_eq!(u32_at(&buf, 24), 0);
+        assert_zeros_from(&buf, 28);
+    }
+
+    #[test]
+    fn encode_end_session_requests() {
+        let buf = encoded(&Request::EndSession, 0x67, 0x00);
+        assert_zeros_from(&buf, 8);
+
+        let buf = encoded(&Request::Reboot, 0x67, 0x01);
+        assert_zeros_from(&buf, 8);
+    }
+
+    #[test]
+    fn decode_responses() {
+        let cases = [
+            ([0x00, 0, 0, 0], ResponseType::FilePart),
+            ([0x64, 0, 0, 0], ResponseType::Session),
+            ([0x65, 0, 0, 0], ResponseType::PitFile),
+            ([0x66, 0, 0, 0], ResponseType::FileTransfer),
+            ([0x67, 0, 0, 0], ResponseType::EndSession),
+        ];
+        for (kind_bytes, kind) in cases {
+            let mut buf = [0; RESPONSE_SIZE];
+            buf[0..4].copy_from_slice(&kind_bytes);
+            buf[4..8].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+
+            let response = Response::decode(&buf).unwrap();
+            assert_eq!(
+                response,
+                Response {
+                    kind,
+                    value: 0x12345678
+                }
+            );
+            assert_eq!(response.encode(), buf);
+        }
+    }
+
+    #[test]
+    fn decode_malformed_responses() {
+        let invalid = |buf: &[u8]| matches!(Response::decode(buf), Err(Error::InvalidPacket(_)));
+        assert!(invalid(&[0x68, 0, 0, 0, 0, 0, 0, 0]), "wrong type");
+        assert!(invalid(&[0x64, 0, 0, 1, 0, 0, 0, 0]), "wrong type");
+        assert!(invalid(&[0x64, 0, 0, 0, 0, 0, 0]), "short buffer");
+        assert!(invalid(&[]), "empty buffer");
+        assert!(invalid(&[0x64, 0, 0, 0, 0, 0, 0, 0, 0]), "long buffer");
+    }
+
+    #[test]
+    fn expect_response_type() {
+        let response = Response {
+            kind: ResponseType::PitFile,
+            value: 7,
+        };
+        assert_eq!(response.expect(ResponseType::PitFile).unwrap(), 7);
+        assert!(matches!(
+            response.expect(ResponseType::Session),
+            Err(Error::UnexpectedResponse { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_malformed_requests() {
+        assert!(Request::decode(&[0x64, 0, 0, 0]).is_err(), "short buffer");
+        let mut buf = Request::PitDump.encode();
+        buf[4] = 0x09;
+        assert!(Request::decode(&buf).is_err(), "unknown request type");
+    }
+}
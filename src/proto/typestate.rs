@@ -0,0 +1,255 @@
+//! Typestate API of the session lifecycle.
+//!
+//! Every state only offers the operations the bootloader accepts next and consumes
+//! itself on the transition, so the compiler rejects e.g. a file transfer before the
+//! total size was announced or receiving the PIT twice:
+//!
+//! ```text
+//! Connected ─handshake→ Handshaken ─begin_session→ InSession ─receive_pit→ PitReceived
+//!                            ↑                         │                      │
+//!                            │                  send_total_size        send_total_size
+//!                            │                         ↓                      │
+//!                            └──────end_session─── TotalSizeSent ←────────────┘
+//!                                                   │        ↑
+//!                                        file_transfer      send
+//!                                                   ↓        │
+//!                                                  Transferring
+//! ```
+//!
+//! ```
+//! use wuotan::proto::typestate::Connected;
+//! use wuotan::proto::{Error, FileTarget, Transport};
+//!
+//! fn flash<T: Transport>(transport: &T, target: &FileTarget, file: &[u8]) -> Result<(), Error> {
+//!     let (session, _) = Connected::new(transport).handshake()?.begin_session()?;
+//!     let (session, _pit) = session.receive_pit()?;
+//!     let session = session.send_total_size(file.len() as u64)?;
+//!     let session = session.file_transfer(target, file.len() as u64)?;
+//!     session.send(&mut &file[..])?.end_session()?.reboot()
+//! }
+//! ```
+//!
+//! A file transfer before the total size was sent doesn't compile:
+//!
+//! ```compile_fail
+//! # use wuotan::proto::typestate::Connected;
+//! # use wuotan::proto::{Error, FileTarget, Transport};
+//! fn flash<T: Transport>(transport: &T, target: &FileTarget, file: &[u8]) -> Result<(), Error> {
+//!     let (session, _) = Connected::new(transport).handshake()?.begin_session()?;
+//!     let session = session.file_transfer(target, file.len() as u64)?;
+//!     session.send(&mut &file[..])?.end_session()?.reboot()
+//! }
+//! ```
+//!
+//! Neither does receiving the PIT twice:
+//!
+//! ```compile_fail
+//! # use wuotan::proto::typestate::Connected;
+//! # use wuotan::proto::{Error, Transport};
+//! fn receive_pit<T: Transport>(transport: &T) -> Result<Vec<u8>, Error> {
+//!     let (session, _) = Connected::new(transport).handshake()?.begin_session()?;
+//!     let (session, _pit) = session.receive_pit()?;
+//!     let (_session, pit) = session.receive_pit()?;
+//!     Ok(pit)
+//! }
+//! ```
+//!
+//! Nor beginning a session without a handshake:
+//!
+//! ```compile_fail
+//! # use wuotan::proto::typestate::Connected;
+//! # use wuotan::proto::{Error, Transport};
+//! fn begin_session<T: Transport>(transport: &T) -> Result<u32, Error> {
+//!     let (_session, packet_size) = Connected::new(transport).begin_session()?;
+//!     Ok(packet_size)
+//! }
+//! ```
+use std::io::Read;
+use std::sync::atomic::AtomicBool;
+
+use super::{Error, Event, FileTarget, Machine, Transport};
+
+/// The states keep no phase of their own, the operations run on the phase of the
+/// transport like the protocol functions.
+struct Inner<'a, T> {
+    transport: &'a T,
+}
+
+impl<'a, T: Transport> Inner<'a, T> {
+    fn run<F>(&mut self, start: F) -> Result<Event, Error>
+    where
+        F: FnOnce(&mut Machine) -> Result<(), Error>,
+    {
+        super::run(self.transport, start)
+    }
+
+    fn end_session(mut self) -> Result<Handshaken<'a, T>, Error> {
+        self.run(Machine::end_session)?;
+        Ok(Handshaken { inner: self })
+    }
+
+    fn reboot(mut self) -> Result<(), Error> {
+        self.run(Machine::reboot)?;
+        Ok(())
+    }
+}
+
+macro_rules! states {
+    ($($(#[$outer:meta])* $name:ident;)+) => {
+        $(
+            $(#[$outer])*
+            pub struct $name<'a, T> {
+                inner: Inner<'a, T>,
+            }
+
+            impl<'a, T> $name<'a, T> {
+                pub fn transport(&self) -> &'a T {
+                    self.inner.transport
+                }
+            }
+        )+
+    };
+}
+
+states! {
+    /// Freshly connected device.
+    Connected;
+    /// Device that answered the handshake, without a session.
+    Handshaken;
+    /// Device with a running session.
+    InSession;
+    /// Session in which the PIT was received.
+    PitReceived;
+    /// Session in which the total size of the files was announced.
+    TotalSizeSent;
+}
+
+/// Session with a started file transfer.
+pub struct Transferring<'a, T> {
+    inner: Inner<'a, T>,
+    machine: Machine,
+}
+
+impl<'a, T> Transferring<'a, T> {
+    pub fn transport(&self) -> &'a T {
+        self.inner.transport
+    }
+}
+
+impl<'a, T: Transport> Connected<'a, T> {
+    pub fn new(transport: &'a T) -> Self {
+        Self {
+            inner: Inner { transport },
+        }
+    }
+
+    pub fn handshake(mut self) -> Result<Handshaken<'a, T>, Error> {
+        self.inner.run(Machine::handshake)?;
+        Ok(Handshaken { inner: self.inner })
+    }
+}
+
+impl<'a, T: Transport> Handshaken<'a, T> {
+    /// Begins a session, returning it with the default packet size of the device.
+    pub fn begin_session(mut self) -> Result<(InSession<'a, T>, u32), Error> {
+        let default_packet_size = match self.inner.run(Machine::begin_session)? {
+            Event::SessionBegun {
+                default_packet_size,
+            } => default_packet_size,
+            _ => unreachable!(),
+        };
+        Ok((InSession { inner: self.inner }, default_packet_size))
+    }
+
+    pub fn reboot(self) -> Result<(), Error> {
+        self.inner.reboot()
+    }
+}
+
+impl<'a, T: Transport> InSession<'a, T> {
+    pub fn setup_file_part_size(&mut self, size: u32) -> Result<(), Error> {
+        self.inner.run(|m| m.setup_file_part_size(size))?;
+        Ok(())
+    }
+
+    /// Flashes a new PIT, repartitioning the device.
+    pub fn flash_pit(&mut self, pit: &[u8]) -> Result<(), Error> {
+        self.inner.run(|m| m.flash_pit(pit.to_vec()))?;
+        Ok(())
+    }
+
+    pub fn receive_pit(mut self) -> Result<(PitReceived<'a, T>, Vec<u8>), Error> {
+        let pit = match self.inner.run(Machine::receive_pit)? {
+            Event::PitReceived(pit) => pit,
+            _ => unreachable!(),
+        };
+        Ok((PitReceived { inner: self.inner }, pit))
+    }
+
+    pub fn send_total_size(mut self, size: u64) -> Result<TotalSizeSent<'a, T>, Error> {
+        self.inner.run(|m| m.send_total_size(size))?;
+        Ok(TotalSizeSent { inner: self.inner })
+    }
+
+    pub fn end_session(self) -> Result<Handshaken<'a, T>, Error> {
+        self.inner.end_session()
+    }
+}
+
+impl<'a, T: Transport> PitReceived<'a, T> {
+    pub fn send_total_size(mut self, size: u64) -> Result<TotalSizeSent<'a, T>, Error> {
+        self.inner.run(|m| m.send_total_size(size))?;
+        Ok(TotalSizeSent { inner: self.inner })
+    }
+
+    pub fn end_session(self) -> Result<Handshaken<'a, T>, Error> {
+        self.inner.end_session()
+    }
+}
+
+impl<'a, T: Transport> TotalSizeSent<'a, T> {
+    /// Starts the transfer of a file of `file_size` bytes to the given target.
+    pub fn file_transfer(
+        self,
+        target: &FileTarget,
+        file_size: u64,
+    ) -> Result<Transferring<'a, T>, Error> {
+        let mut machine = Machine::resume(self.inner.transport.phase());
+        machine.file_transfer(target, file_size)?;
+        Ok(Transferring {
+            inner: self.inner,
+            machine,
+        })
+    }
+
+    pub fn end_session(self) -> Result<Handshaken<'a, T>, Error> {
+        self.inner.end_session()
+    }
+}
+
+impl<'a, T: Transport> Transferring<'a, T> {
+    /// Sends the file data.
+    pub fn send<R: Read>(mut self, file: &mut R) -> Result<TotalSizeSent<'a, T>, Error> {
+        let transport = self.inner.transport;
+        let result = super::drive(transport, &mut self.machine, Some(file));
+        transport.set_phase(self.machine.phase());
+        result?;
+        Ok(TotalSizeSent { inner: self.inner })
+    }
+
+    /// Sends the file data, stopping after the current batch once `stop` is set.
+    ///
+    /// Returns whether the whole file was transferred.
+    pub fn send_until<R: Read>(
+        mut self,
+        file: &mut R,
+        stop: &AtomicBool,
+    ) -> Result<(TotalSizeSent<'a, T>, bool), Error> {
+        let transport = self.inner.transport;
+        let result = super::drive_until(transport, &mut self.machine, Some(file), stop);
+        transport.set_phase(self.machine.phase());
+        let event = result?;
+        let done = matches!(event, Event::FileTransferred);
+        Ok((TotalSizeSent { inner: self.inner }, done))
+    }
+}
@@ -1,40 +1,7 @@
 use std::io::{self, Read};
 use std::iter::Iterator;
-
-use super::Error;
-use crate::device::Handle;
-
-pub trait HandleExt {
-    fn with_post_read_op<F, T>(&self, f: F) -> Result<T, Error>
-    where
-        F: FnMut(&Self) -> Result<T, Error>;
-
-    fn with_post_write_op<F, T>(&self, f: F) -> Result<T, Error>
-    where
-        F: FnMut(&Self) -> Result<T, Error>;
-}
-
-impl HandleExt for Handle {
-    fn with_post_read_op<F, T>(&self, mut f: F) -> Result<T, Error>
-    where
-        F: FnMut(&Self) -> Result<T, Error>,
-    {
-        let ret = f(self)?;
-        tracing::trace!("read bulk with empty slice");
-        self.read(&mut [])?;
-        Ok(ret)
-    }
-
-    fn with_post_write_op<F, T>(&self, mut f: F) -> Result<T, Error>
-    where
-        F: FnMut(&Self) -> Result<T, Error>,
-    {
-        let ret = f(self)?;
-        tracing::trace!("write bulk with empty slice");
-        self.write(&[])?;
-        Ok(ret)
-    }
-}
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
 
 pub fn fill_buf<R: Read>(r: &mut R, mut buf: &mut [u8]) -> io::Result<usize> {
     let mut count = 0;
@@ -50,6 +17,79 @@ pub fn fill_buf<R: Read>(r: &mut R, mut buf: &mut [u8]) -> io::Result<usize> {
     Ok(count)
 }
 
+/// Number of chunks read ahead of the transfer.
+const READ_AHEAD_CHUNKS: usize = 4;
+
+/// Runs `f` with a reader that is filled from `reader` on a separate thread, so
+/// reading the file overlaps with the USB transfers.
+///
+/// The chunks are read into a fixed set of reused buffers of `chunk_size` bytes.
+pub fn read_ahead<R, F, T>(reader: R, chunk_size: usize, f: F) -> T
+where
+    R: Read + Send,
+    F: FnOnce(&mut ReadAhead) -> T,
+{
+    thread::scope(|scope| {
+        let (filled_tx, filled_rx) = mpsc::sync_channel(READ_AHEAD_CHUNKS);
+        let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+        for _ in 0..READ_AHEAD_CHUNKS {
+            let _ = empty_tx.send(vec![0; chunk_size]);
+        }
+
+        scope.spawn(move || {
+            let mut reader = reader;
+            // stops when the reader is at the end or the receiving side is dropped
+            while let Ok(mut buf) = empty_rx.recv() {
+                let result = fill_buf(&mut reader, &mut buf);
+                let end = !matches!(result, Ok(n) if n == buf.len());
+                if filled_tx.send(result.map(|n| (buf, n))).is_err() || end {
+                    break;
+                }
+            }
+        });
+
+        let mut reader = ReadAhead {
+            filled: filled_rx,
+            empty: empty_tx,
+            current: None,
+        };
+        f(&mut reader)
+    })
+}
+
+/// Reader side of [`read_ahead`].
+pub struct ReadAhead {
+    filled: Receiver<io::Result<(Vec<u8>, usize)>>,
+    empty: Sender<Vec<u8>>,
+    /// Buffer being read with its length and read position.
+    current: Option<(Vec<u8>, usize, usize)>,
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some((buf, len, pos)) = &mut self.current {
+                if *pos < *len {
+                    let n = out.len().min(*len - *pos);
+                    out[..n].copy_from_slice(&buf[*pos..*pos + n]);
+                    *pos += n;
+                    return Ok(n);
+                }
+                if let Some((buf, _, _)) = self.current.take() {
+                    let _ = self.empty.send(buf);
+                }
+            }
+            match self.filled.recv() {
+                Ok(Ok((buf, len))) => self.current = Some((buf, len, 0)),
+                Ok(Err(e)) => return Err(e),
+                // the reading thread stopped at the end of the reader
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct BatchIterator {
     bytes_left: u64,
     chunk_size: u32,
@@ -97,6 +137,7 @@ impl Iterator for BatchIterator {
     }
 }
 
+#[derive(Debug)]
 pub struct Batch {
     chunks: u32,
     chunk_size: u32,
@@ -121,7 +162,76 @@ impl Batch {
     }
 
     #[inline]
-    pub fn chunks(&self) -> impl Iterator<Item = u32> {
-        0..self.chunks
+    pub fn chunks(&self) -> u32 {
+        self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader of `data` failing once it's read.
+    struct Failing<'a> {
+        data: &'a [u8],
+    }
+
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::other("disk failed"));
+            }
+            self.data.read(buf)
+        }
+    }
+
+    /// Reads everything in reads of up to `size` bytes.
+    fn read_all(reader: &mut ReadAhead, size: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut buf = vec![0; size];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn read_ahead_in_order() {
+        // more chunks than buffers, the last one is short
+        let data: Vec<u8> = (0..16 * 10 + 7).map(|i| i as u8).collect();
+        for size in [1, 5, 16, 100] {
+            let read = read_ahead(&data[..], 16, |r| read_all(r, size)).unwrap();
+            assert_eq!(read, data, "reads of {} bytes", size);
+        }
+
+        let data = &data[..16 * 6];
+        assert_eq!(read_ahead(data, 16, |r| read_all(r, 7)).unwrap(), data);
+        assert!(read_ahead(&[][..], 16, |r| read_all(r, 7))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn read_ahead_error() {
+        let data: Vec<u8> = (0..16 * 5 + 3).map(|i| i as u8).collect();
+        let (read, result) = read_ahead(Failing { data: &data }, 16, |r| {
+            let mut read = vec![0; 16 * 5];
+            r.read_exact(&mut read).unwrap();
+            (read, r.read(&mut [0; 16]))
+        });
+        assert_eq!(read, data[..16 * 5]);
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), "disk failed");
+    }
+
+    #[test]
+    fn read_ahead_stop_early() {
+        let data = vec![1; 16 * 100];
+        let mut buf = [0; 3];
+        let n = read_ahead(&data[..], 16, |r| r.read(&mut buf)).unwrap();
+        assert_eq!(buf[..n], [1; 3][..n]);
     }
 }
@@ -0,0 +1,368 @@
+//! Recording and replaying of the bulk transfers of a session.
+//!
+//! A recording starts with the magic `WREC` and a version number followed by the
+//! transfers in the order they happened. All integers are little endian.
+//!
+//! | field     | type | description                                           |
+//! |-----------|------|-------------------------------------------------------|
+//! | direction | u8   | `0` = out (host to device), `1` = in (device to host)  |
+//! | status    | u8   | `0` = ok, otherwise the [`rusb::Error`] code           |
+//! | timestamp | u64  | microseconds since the start of the recording         |
+//! | length    | u32  | length of the buffer passed to the transfer            |
+//! | size      | u32  | number of transferred bytes that follow                |
+//! | data      | [u8] | the transferred bytes                                 |
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::proto::{Error, Phase, Transport};
+
+const MAGIC: &[u8; 4] = b"WREC";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+/// A completed bulk transfer on one of the endpoints of a [`Handle`](crate::device::Handle).
+#[derive(Debug)]
+pub struct BulkTransfer<'a> {
+    pub endpoint: u8,
+    /// Length of the buffer passed to the transfer.
+    pub length: usize,
+    pub submitted: Instant,
+    pub completed: Instant,
+    /// The transferred bytes or the error of the failed transfer.
+    pub result: Result<&'a [u8], &'a rusb::Error>,
+}
+
+impl BulkTransfer<'_> {
+    pub fn direction(&self) -> Direction {
+        if self.endpoint & rusb::constants::LIBUSB_ENDPOINT_IN != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+}
+
+/// Receives the bulk transfers of a [`Handle`](crate::device::Handle).
+pub trait Capture: Send {
+    fn capture(&mut self, transfer: &BulkTransfer<'_>) -> io::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct Transfer {
+    pub direction: Direction,
+    pub timestamp: Duration,
+    pub length: u32,
+    pub result: Result<Vec<u8>, rusb::Error>,
+}
+
+impl Transfer {
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Option<Transfer>> {
+        let direction = match r.read_u8() {
+            Ok(0) => Direction::Out,
+            Ok(1) => Direction::In,
+            Ok(_) => return Err(io::ErrorKind::InvalidData.into()),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let status = r.read_u8()?;
+        let timestamp = Duration::from_micros(r.read_u64::<LE>()?);
+        let length = r.read_u32::<LE>()?;
+        let size = r.read_u32::<LE>()?;
+        let mut data = vec![0; size as usize];
+        r.read_exact(&mut data)?;
+
+        let result = match status {
+            0 => Ok(data),
+            code => Err(error_from_code(code)),
+        };
+        Ok(Some(Transfer {
+            direction,
+            timestamp,
+            length,
+            result,
+        }))
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let (status, data) = match &self.result {
+            Ok(data) => (0, &data[..]),
+            Err(e) => (error_code(e), &[][..]),
+        };
+        w.write_u8(match self.direction {
+            Direction::Out => 0,
+            Direction::In => 1,
+        })?;
+        w.write_u8(status)?;
+        w.write_u64::<LE>(self.timestamp.as_micros() as u64)?;
+        w.write_u32::<LE>(self.length)?;
+        w.write_u32::<LE>(data.len() as u32)?;
+        w.write_all(data)
+    }
+}
+
+/// Reads the transfers of a recording.
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC || inner.read_u32::<LE>()? != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a wuotan recording",
+            ));
+        }
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Transfer>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Transfer::read_from(&mut self.inner).transpose()
+    }
+}
+
+/// Writes every transfer of a session to a recording.
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_u32::<LE>(VERSION)?;
+
+        Ok(Self {
+            out,
+            start: Instant::now(),
+        })
+    }
+}
+
+impl Capture for Recorder {
+    fn capture(&mut self, transfer: &BulkTransfer<'_>) -> io::Result<()> {
+        let transfer = Transfer {
+            direction: transfer.direction(),
+            timestamp: transfer.submitted.saturating_duration_since(self.start),
+            length: transfer.length as u32,
+            result: transfer.result.map(<[u8]>::to_vec).map_err(|e| *e),
+        };
+        transfer.write_to(&mut self.out)
+    }
+}
+
+/// Transport that plays back a recording.
+///
+/// Writes have to match the recorded outgoing data and reads return the recorded
+/// incoming data, so the `proto` functions can be run against a recorded session.
+pub struct Replay {
+    reader: Mutex<Reader<BufReader<File>>>,
+    phase: Mutex<Phase>,
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            reader: Mutex::new(Reader::open(path)?),
+            phase: Mutex::new(Phase::Connected),
+        })
+    }
+
+    fn next(&self, direction: Direction) -> Result<Transfer, Error> {
+        let mut reader = self.reader.lock().expect("poisoned lock");
+        match reader.next() {
+            Some(Ok(transfer)) if transfer.direction == direction => Ok(transfer),
+            Some(Ok(transfer)) => Err(Error::Replay(format!(
+                "expected {:?} transfer, recording has {:?} at {:?}",
+                direction, transfer.direction, transfer.timestamp
+            ))),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(Error::Replay("end of recording".into())),
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.next(Direction::In)?.result?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        let transfer = self.next(Direction::Out)?;
+        let data = transfer.result?;
+        if data != buf {
+            return Err(Error::Replay(format!(
+                "written data differs from the recording at {:?}",
+                transfer.timestamp
+            )));
+        }
+        Ok(data.len())
+    }
+
+    fn phase(&self) -> Phase {
+        *self.phase.lock().expect("poisoned lock")
+    }
+
+    fn set_phase(&self, phase: Phase) {
+        *self.phase.lock().expect("poisoned lock") = phase;
+    }
+}
+
+fn error_code(e: &rusb::Error) -> u8 {
+    match e {
+        rusb::Error::Io => 1,
+        rusb::Error::InvalidParam => 2,
+        rusb::Error::Access => 3,
+        rusb::Error::NoDevice => 4,
+        rusb::Error::NotFound => 5,
+        rusb::Error::Busy => 6,
+        rusb::Error::Timeout => 7,
+        rusb::Error::Overflow => 8,
+        rusb::Error::Pipe => 9,
+        rusb::Error::Interrupted => 10,
+        rusb::Error::NoMem => 11,
+        rusb::Error::NotSupported => 12,
+        rusb::Error::BadDescriptor => 13,
+        rusb::Error::Other => 14,
+    }
+}
+
+fn error_from_code(code: u8) -> rusb::Error {
+    match code {
+        1 => rusb::Error::Io,
+        2 => rusb::Error::InvalidParam,
+        3 => rusb::Error::Access,
+        4 => rusb::Error::NoDevice,
+        5 => rusb::Error::NotFound,
+        6 => rusb::Error::Busy,
+        7 => rusb::Error::Timeout,
+        8 => rusb::Error::Overflow,
+        9 => rusb::Error::Pipe,
+        10 => rusb::Error::Interrupted,
+        11 => rusb::Error::NoMem,
+        12 => rusb::Error::NotSupported,
+        13 => rusb::Error::BadDescriptor,
+        _ => rusb::Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::proto::packet::{Request, Response, ResponseType};
+    use crate::proto::{self, Error};
+
+    /// Writes a recording of the transfers to a temporary file.
+    fn recording(name: &str, transfers: &[(Direction, Vec<u8>)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wuotan-{}-{}.rec", std::process::id(), name));
+        let mut out = BufWriter::new(File::create(&path).unwrap());
+        out.write_all(MAGIC).unwrap();
+        out.write_u32::<LE>(VERSION).unwrap();
+        for (i, (direction, data)) in transfers.iter().enumerate() {
+            let transfer = Transfer {
+                direction: *direction,
+                timestamp: Duration::from_millis(i as u64),
+                length: data.len() as u32,
+                result: Ok(data.clone()),
+            };
+            transfer.write_to(&mut out).unwrap();
+        }
+        out.flush().unwrap();
+        path
+    }
+
+    fn request(request: Request) -> (Direction, Vec<u8>) {
+        (Direction::Out, request.encode())
+    }
+
+    fn response(kind: ResponseType, value: u32) -> (Direction, Vec<u8>) {
+        (Direction::In, Response { kind, value }.encode().to_vec())
+    }
+
+    /// Transfers of a session receiving the PIT in parts of 500 bytes.
+    fn pit_session(begin_session: Request, pit: &[u8]) -> Vec<(Direction, Vec<u8>)> {
+        let mut transfers = vec![
+            (Direction::Out, b"ODIN".to_vec()),
+            (Direction::In, b"LOKE".to_vec()),
+            request(begin_session),
+            response(ResponseType::Session, 0x100000),
+            request(Request::PitDump),
+            response(ResponseType::PitFile, pit.len() as u32),
+        ];
+        for (index, part) in pit.chunks(500).enumerate() {
+            transfers.push(request(Request::PitPart {
+                index: index as u32,
+            }));
+            transfers.push((Direction::In, part.to_vec()));
+        }
+        transfers.extend([
+            (Direction::In, vec![]),
+            request(Request::PitEnd { size: 0 }),
+            response(ResponseType::PitFile, 0),
+            request(Request::EndSession),
+            response(ResponseType::EndSession, 0),
+        ]);
+        transfers
+    }
+
+    #[test]
+    fn replay_pit_session() {
+        let pit: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        let path = recording(
+            "pit-session",
+            &pit_session(Request::BeginSession { version: 4 }, &pit),
+        );
+        let replay = Replay::open(&path).unwrap();
+
+        proto::handshake(&replay).unwrap();
+        assert_eq!(proto::begin_session(&replay).unwrap(), 0x100000);
+        assert_eq!(proto::receive_pit(&replay).unwrap(), pit);
+        proto::end_session(&replay).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_diverging_request() {
+        let path = recording(
+            "diverging-request",
+            &pit_session(Request::BeginSession { version: 3 }, &[0; 10]),
+        );
+        let replay = Replay::open(&path).unwrap();
+
+        proto::handshake(&replay).unwrap();
+        let err = proto::begin_session(&replay).unwrap_err();
+        assert!(matches!(err, Error::Replay(_)), "{:?}", err);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+//! Odin session owning the device handle.
+//!
+//! [`Session::open`] claims the interface and sets up the session, dropping the
+//! session ends it and releases the interface again, so an early return can't leave
+//! the device with a dangling session. Use [`Session::finish`] or [`Session::reboot`]
+//! to see the errors of the teardown. [`Session::connect`] only does the handshake,
+//! e.g. to reboot the device without a session.
+use std::io::Read;
+use std::sync::atomic::AtomicBool;
+
+use crate::device::Handle;
+use crate::proto::{self, Error, Event, FileTarget, Machine, Phase, Transport};
+
+/// Size of the file parts announced if the device has a different default.
+const FILE_PART_SIZE: u32 = 1024 * 1024;
+
+/// Transport of a device with an interface to claim for the session.
+pub trait Interface: Transport {
+    fn claim(&mut self) -> Result<(), Error>;
+
+    /// Resets the device, a new handshake is needed afterwards.
+    fn reset(&mut self) -> Result<(), Error>;
+
+    fn release(&mut self) -> Result<(), Error>;
+}
+
+impl Interface for Handle {
+    fn claim(&mut self) -> Result<(), Error> {
+        Ok(Handle::claim(self)?)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(Handle::reset(self)?)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        Ok(Handle::release(self)?)
+    }
+}
+
+pub struct Session<T: Interface = Handle> {
+    handle: T,
+    default_packet_size: u32,
+    closed: bool,
+}
+
+impl<T: Interface> Session<T> {
+    /// Claims the interface of the device, does the handshake and begins the session.
+    pub fn open(handle: T) -> Result<Self, Error> {
+        let mut session = Self::connect(handle)?;
+        session.begin()?;
+        Ok(session)
+    }
+
+    /// Claims the interface of the device and does the handshake, without beginning
+    /// a session.
+    pub fn connect(mut handle: T) -> Result<Self, Error> {
+        handle.claim()?;
+        let mut session = Self {
+            handle,
+            default_packet_size: 0,
+            closed: false,
+        };
+        session.handle.reset()?;
+        session.run(Machine::handshake)?;
+        Ok(session)
+    }
+
+    /// Begins the session after [`connect`](Self::connect).
+    pub fn begin(&mut self) -> Result<(), Error> {
+        match self.run(Machine::begin_session)? {
+            Event::SessionBegun {
+                default_packet_size,
+            } => self.default_packet_size = default_packet_size,
+            _ => unreachable!(),
+        }
+        if self.default_packet_size != 0 {
+            self.run(|m| m.setup_file_part_size(FILE_PART_SIZE))?;
+        }
+        Ok(())
+    }
+
+    pub fn handle(&self) -> &T {
+        &self.handle
+    }
+
+    /// Default packet size reported by the device when the session began.
+    pub fn default_packet_size(&self) -> u32 {
+        self.default_packet_size
+    }
+
+    pub fn receive_pit(&mut self) -> Result<Vec<u8>, Error> {
+        match self.run(Machine::receive_pit)? {
+            Event::PitReceived(pit) => Ok(pit),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Flashes a new PIT, repartitioning the device.
+    pub fn flash_pit(&mut self, pit: &[u8]) -> Result<(), Error> {
+        self.run(|m| m.flash_pit(pit.to_vec()))?;
+        Ok(())
+    }
+
+    pub fn send_total_size(&mut self, size: u64) -> Result<(), Error> {
+        self.run(|m| m.send_total_size(size))?;
+        Ok(())
+    }
+
+    /// Transfers a file, it's read ahead of the transfer on a separate thread.
+    pub fn file_transfer<R: Read + Send>(
+        &mut self,
+        target: &FileTarget,
+        file: &mut R,
+        file_size: u64,
+    ) -> Result<(), Error> {
+        let handle = &self.handle;
+        proto::run_with(
+            handle,
+            |m| m.file_transfer(target, file_size),
+            |m| {
+                proto::read_ahead(file.take(file_size), FILE_PART_SIZE as usize, |file| {
+                    proto::drive(handle, m, Some(file))
+                })
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Like [`file_transfer`](Self::file_transfer), but stops after the current batch
+    /// once `stop` is set.
+    ///
+    /// The file data of every chunk is passed to `sent` once it's written to the
+    /// device, unlike the file it isn't read ahead. Returns whether the whole file
+    /// was transferred.
+    pub fn file_transfer_until<R, S>(
+        &mut self,
+        target: &FileTarget,
+        file: &mut R,
+        file_size: u64,
+        stop: &AtomicBool,
+        sent: S,
+    ) -> Result<bool, Error>
+    where
+        R: Read + Send,
+        S: FnMut(&[u8]),
+    {
+        let handle = &self.handle;
+        let event = proto::run_with(
+            handle,
+            |m| m.file_transfer(target, file_size),
+            |m| {
+                proto::read_ahead(file.take(file_size), FILE_PART_SIZE as usize, |file| {
+                    proto::drive_until_sent(handle, m, Some(file), stop, sent)
+                })
+            },
+        )?;
+        Ok(matches!(event, Event::FileTransferred))
+    }
+
+    /// Ends the session and releases the interface.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.close()
+    }
+
+    /// Ends the session if one was begun, reboots the device and releases the
+    /// interface.
+    pub fn reboot(mut self) -> Result<(), Error> {
+        self.end_session()?;
+        self.run(Machine::reboot)?;
+        self.close()
+    }
+
+    fn run<F>(&mut self, start: F) -> Result<Event, Error>
+    where
+        F: FnOnce(&mut Machine) -> Result<(), Error>,
+    {
+        proto::run(&self.handle, start)
+    }
+
+    fn end_session(&mut self) -> Result<(), Error> {
+        if let Phase::Session | Phase::PitReceived | Phase::TotalSizeSent = self.handle.phase() {
+            self.run(Machine::end_session)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        let result = self.end_session();
+        self.handle.release()?;
+        result
+    }
+}
+
+impl<T: Interface> Drop for Session<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            tracing::warn!("failed to close session: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::proto::packet::{Request, Response, ResponseType, HANDSHAKE_REQUEST};
+
+    /// Interface state of a [`Scripted`] device, shared with the test.
+    #[derive(Default)]
+    struct Log {
+        requests: RefCell<Vec<Request>>,
+        claimed: Cell<bool>,
+        released: Cell<bool>,
+    }
+
+    /// Device answering the reads with scripted data and logging the requests.
+    struct Scripted {
+        reads: RefCell<VecDeque<Vec<u8>>>,
+        phase: Cell<Phase>,
+        log: Rc<Log>,
+    }
+
+    impl Scripted {
+        fn new(responses: &[ResponseType]) -> (Self, Rc<Log>) {
+            let mut reads = vec![b"LOKE".to_vec()];
+            for &kind in responses {
+                reads.push(Response { kind, value: 0 }.encode().to_vec());
+            }
+            let log = Rc::new(Log::default());
+            let device = Self {
+                reads: RefCell::new(reads.into()),
+                phase: Cell::new(Phase::Connected),
+                log: Rc::clone(&log),
+            };
+            (device, log)
+        }
+    }
+
+    impl Transport for Scripted {
+        fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+            let data = self.reads.borrow_mut().pop_front().expect("scripted read");
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+
+        fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+            if buf != HANDSHAKE_REQUEST {
+                let request = Request::decode(buf)?;
+                self.log.requests.borrow_mut().push(request);
+            }
+            Ok(buf.len())
+        }
+
+        fn phase(&self) -> Phase {
+            self.phase.get()
+        }
+
+        fn set_phase(&self, phase: Phase) {
+            self.phase.set(phase);
+        }
+    }
+
+    impl Interface for Scripted {
+        fn claim(&mut self) -> Result<(), Error> {
+            self.log.claimed.set(true);
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<(), Error> {
+            self.phase.set(Phase::Connected);
+            Ok(())
+        }
+
+        fn release(&mut self) -> Result<(), Error> {
+            self.log.released.set(true);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn end_session_on_drop() {
+        let (device, log) = Scripted::new(&[ResponseType::Session, ResponseType::EndSession]);
+        let session = Session::open(device).unwrap();
+        assert!(log.claimed.get());
+        assert!(!log.released.get());
+
+        drop(session);
+        assert_eq!(
+            log.requests.borrow().last(),
+            Some(&Request::EndSession),
+            "session not ended"
+        );
+        assert!(log.released.get());
+    }
+
+    #[test]
+    fn reboot_without_session() {
+        let (device, log) = Scripted::new(&[ResponseType::EndSession]);
+        Session::connect(device).unwrap().reboot().unwrap();
+        assert_eq!(*log.requests.borrow(), [Request::Reboot]);
+        assert!(log.released.get());
+    }
+}